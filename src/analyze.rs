@@ -0,0 +1,174 @@
+use std::{collections::HashMap, error::Error};
+
+use crate::{
+    encoders::{auto, bwt::Bwt, encoder::format_pipeline},
+    utils::*,
+};
+
+/*
+    analyze: profiles a file before picking a pipeline for it.
+
+    - Byte histogram: how many distinct bytes there are, and the most common ones.
+    - Order-0/1/2 entropy: bits per byte needed by a coder that knows the previous 0, 1 or 2 bytes.
+      Order-0 is what Huffman gets close to, and the drop from there to order-1/2 hints at how much a
+      context-based transform (like the BWT) has to work with.
+    - Run lengths: how the input splits into runs of the same byte, which is what RLE and RLE1 feed on.
+    - BWT runs: the same count after a BWT, estimated from the --auto samples. Fewer (longer) runs mean MTF + RLE
+      will do well.
+    - Per-pipeline estimates: every --auto candidate compressed on those samples, dry-run style, without writing anything.
+*/
+
+// Number of bytes shown in the histogram
+const TOP_BYTES: usize = 16;
+
+// Length of the longest bar in the histogram
+const BAR_WIDTH: usize = 40;
+
+// Run length buckets, as (lowest, highest) lengths
+const RUN_BUCKETS: [(usize, usize); 7] = [
+    (1, 1),
+    (2, 2),
+    (3, 3),
+    (4, 7),
+    (8, 15),
+    (16, 255),
+    (256, usize::MAX),
+];
+
+/// Empirical entropy of `data` in bits per byte, given the previous `order` bytes.
+/// The first `order` bytes have no full context, and are left out.
+fn entropy(data: &[u8], order: usize) -> f64 {
+    if order == 0 {
+        return order0_entropy(data);
+    }
+    if data.len() <= order {
+        return 0.0;
+    }
+
+    let mut contexts: HashMap<&[u8], usize> = HashMap::new();
+    let mut symbols: HashMap<&[u8], usize> = HashMap::new();
+    for window in data.windows(order + 1) {
+        *contexts.entry(&window[..order]).or_default() += 1;
+        *symbols.entry(window).or_default() += 1;
+    }
+
+    let total = (data.len() - order) as f64;
+    symbols
+        .iter()
+        .map(|(window, &count)| {
+            let context = contexts[&window[..order]] as f64;
+            count as f64 / total * (context / count as f64).log2()
+        })
+        .sum()
+}
+
+/// Lengths of the runs of the same byte in `data`
+fn runs(data: &[u8]) -> Vec<usize> {
+    data.chunk_by(|a, b| a == b).map(|run| run.len()).collect()
+}
+
+/// Profiles every file in `paths`
+pub fn print(paths: &[String]) -> Result<(), Box<dyn Error>> {
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        analyze(path, &read_input(path)?);
+    }
+    Ok(())
+}
+
+fn analyze(path: &str, data: &[u8]) {
+    let percent = |count: usize| count as f64 / data.len().max(1) as f64 * 100.0;
+
+    let mut freq_map = [0usize; 256];
+    data.iter().for_each(|&b| freq_map[b as usize] += 1);
+    let distinct = freq_map.iter().filter(|&&count| count > 0).count();
+
+    println!("{path}: {} bytes, {distinct} distinct", data.len());
+
+    println!();
+    println!(
+        "  Entropy:   order-0 {:.3}, order-1 {:.3}, order-2 {:.3} bits/byte",
+        entropy(data, 0),
+        entropy(data, 1),
+        entropy(data, 2)
+    );
+    println!(
+        "             (order-0 alone would take about {} bytes)",
+        (entropy(data, 0) * data.len() as f64 / 8.0).ceil()
+    );
+
+    println!();
+    println!("  Most common bytes:");
+    let mut bytes: Vec<(u8, usize)> = (0..=255u8)
+        .map(|b| (b, freq_map[b as usize]))
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    bytes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let most = bytes.first().map_or(1, |&(_, count)| count);
+    for &(byte, count) in bytes.iter().take(TOP_BYTES) {
+        println!(
+            "    {byte:#04x} {:<4} {count:>10} {:>6.2}%  {}",
+            printable(byte),
+            percent(count),
+            "#".repeat((count * BAR_WIDTH).div_ceil(most))
+        );
+    }
+    if bytes.len() > TOP_BYTES {
+        let rest: usize = bytes[TOP_BYTES..].iter().map(|&(_, count)| count).sum();
+        println!(
+            "    {:<9} {rest:>10} {:>6.2}%",
+            format!("{} more", bytes.len() - TOP_BYTES),
+            percent(rest)
+        );
+    }
+
+    println!();
+    println!("  Runs of the same byte:");
+    let lengths = runs(data);
+    for (low, high) in RUN_BUCKETS {
+        let bucket: Vec<usize> = lengths
+            .iter()
+            .copied()
+            .filter(|len| (low..=high).contains(len))
+            .collect();
+        let label = match (low, high) {
+            (_, usize::MAX) => format!("{low}+"),
+            _ if low == high => format!("{low}"),
+            _ => format!("{low}-{high}"),
+        };
+        println!(
+            "    {label:<8} {:>10} runs, {:>6.2}% of the data",
+            bucket.len(),
+            percent(bucket.iter().sum())
+        );
+    }
+
+    // Sampled like --auto does, since the suffix sort is the slow part
+    let samples = auto::samples(data);
+    let sampled: usize = samples.iter().map(|s| s.len()).sum();
+    let sample_runs: usize = samples
+        .iter()
+        .map(|sample| runs(&Bwt::encode(sample.to_vec(), 0)).len())
+        .sum();
+    let bwt_runs = match sampled {
+        0 => 0,
+        _ => sample_runs * data.len() / sampled,
+    };
+    println!(
+        "    {} runs in total (average length {:.2}), about {bwt_runs} after a BWT (average length {:.2})",
+        lengths.len(),
+        data.len() as f64 / lengths.len().max(1) as f64,
+        data.len() as f64 / bwt_runs.max(1) as f64
+    );
+
+    println!();
+    println!("  Estimated output per pipeline:");
+    let names: Vec<String> = auto::candidates().iter().map(|p| format_pipeline(p)).collect();
+    let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+    for (pipeline, name) in auto::candidates().iter().zip(names.iter()) {
+        let estimate = auto::estimate(data, pipeline);
+        println!("    {name:<width$} {estimate:>10} {:>7.2}%", percent(estimate));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    encoders::{
+        encoder::{format_pipeline, ParseError, Tokens},
+        limits::DecodeLimits,
+    },
+    utils::*,
+};
+
+/*
+    Archive layout (.pka):
+
+    "PKA" [version] [members]... [index] [index offset]
+
+    Every member is a complete .pkz container (header and all), so each one carries its own pipeline.
+    The index sits at the end, like zip's central directory, so members can be written out as they are compressed:
+
+    [varint number of entries] ([kind] [varint path length] [path] [varint size] [varint offset] [varint compressed length])...
+
+    The index offset is the last 8 bytes of the archive (u64, big endian).
+    Paths are relative and always use '/' as the separator. Directories have no data, and are only there
+    so empty directories survive a round trip.
+*/
+const MAGIC: &[u8; 3] = b"PKA";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File = 0,
+    Directory = 1,
+}
+
+/// A member of the archive, as stored in the index
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub path: String,
+    /// Original size of the member
+    pub size: usize,
+    /// Where the member's .pkz container starts in the archive
+    pub offset: usize,
+    pub compressed_len: usize,
+}
+
+/// Packs `paths` into an archive, compressing every file with the pipeline picked by `args`.
+/// An existing archive is only overwritten with -f.
+pub fn create(archive: &str, paths: &[String], args: &Args) -> Result<(), Box<dyn Error>> {
+    if Path::new(archive).exists() && !args.force {
+        return Err(format!("{archive} already exists, pass -f to overwrite it").into());
+    }
+    // The archive being replaced may sit under one of `paths`, and shouldn't end up inside itself
+    let existing = fs::canonicalize(archive).ok();
+
+    let mut output = MAGIC.to_vec();
+    output.push(VERSION);
+
+    let mut entries = vec![];
+    for path in paths {
+        collect(Path::new(path), &mut entries)?;
+    }
+
+    let mut index = vec![];
+    for (kind, path) in entries {
+        // `.` and the like have no name of their own, only their contents go in
+        let name = archive_path(&path);
+        if name.is_empty() {
+            continue;
+        }
+        if existing.is_some() && fs::canonicalize(&path).ok() == existing {
+            log::info!("Skipping {}, it is the archive itself", path.display());
+            continue;
+        }
+        let mut entry = Entry {
+            kind,
+            path: name,
+            size: 0,
+            offset: output.len(),
+            compressed_len: 0,
+        };
+
+        if kind == EntryKind::File {
+            let data = fs::read(&path)?;
+            let pipeline = args.resolve_pipeline(&data);
+            log::info!(
+                "Adding {} ({} bytes) with {}",
+                entry.path,
+                data.len(),
+                format_pipeline(&pipeline)
+            );
+            entry.size = data.len();
+            let compressed = Tokens::new(pipeline).compress(data);
+            entry.compressed_len = compressed.len();
+            output.extend(compressed);
+        } else {
+            log::info!("Adding {}/", entry.path);
+        }
+        index.push(entry);
+    }
+
+    let index_offset = output.len() as u64;
+    push_varint(&mut output, index.len() as u64);
+    for entry in index.iter() {
+        output.push(entry.kind as u8);
+        push_varint(&mut output, entry.path.len() as u64);
+        output.extend(entry.path.as_bytes());
+        push_varint(&mut output, entry.size as u64);
+        push_varint(&mut output, entry.offset as u64);
+        push_varint(&mut output, entry.compressed_len as u64);
+    }
+    output.extend(index_offset.to_be_bytes());
+
+    log::info!("Wrote {} entries to {archive} ({} bytes)", index.len(), output.len());
+    fs::write(archive, output)?;
+    Ok(())
+}
+
+/// Unpacks every member of `archive` into `directory`. Existing files are only overwritten with `force`.
+pub fn extract(archive: &str, directory: &str, force: bool, limits: &DecodeLimits) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(archive)?;
+    let index = read_index(&data)?;
+
+    for entry in index {
+        let path = Path::new(directory).join(safe_path(&entry.path)?);
+        match entry.kind {
+            EntryKind::Directory => {
+                log::info!("Creating {}", path.display());
+                fs::create_dir_all(&path)?;
+            }
+            EntryKind::File => {
+                if path.exists() && !force {
+                    return Err(format!("{} already exists, pass -f to overwrite it", path.display()).into());
+                }
+                log::info!("Extracting {}", path.display());
+                let decoded = read_member(&data, &entry, limits)?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, decoded)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints the index of `archive` as a table
+pub fn list(archive: &str) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(archive)?;
+    let index = read_index(&data)?;
+
+    println!("{:<5} {:>12} {:>12} {:>7}  Path", "Type", "Size", "Compressed", "Ratio");
+    for entry in index.iter() {
+        match entry.kind {
+            EntryKind::Directory => println!("{:<5} {:>12} {:>12} {:>7}  {}/", "dir", "-", "-", "-", entry.path),
+            EntryKind::File => println!(
+                "{:<5} {:>12} {:>12} {:>6.2}%  {}",
+                "file",
+                entry.size,
+                entry.compressed_len,
+                ratio(entry.compressed_len, entry.size),
+                entry.path
+            ),
+        }
+    }
+
+    let size: usize = index.iter().map(|e| e.size).sum();
+    let files = index.iter().filter(|e| e.kind == EntryKind::File).count();
+    println!(
+        "{:<5} {:>12} {:>12} {:>6.2}%  {files} files, {} directories",
+        "total",
+        size,
+        data.len(),
+        ratio(data.len(), size),
+        index.len() - files
+    );
+    Ok(())
+}
+
+/// Recursively collects `path` and everything below it, directories before their contents
+fn collect(path: &Path, entries: &mut Vec<(EntryKind, PathBuf)>) -> Result<(), Box<dyn Error>> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        entries.push((EntryKind::Directory, path.to_path_buf()));
+
+        let mut children: Vec<PathBuf> = fs::read_dir(path)?
+            .map(|child| child.map(|c| c.path()))
+            .collect::<Result<_, _>>()?;
+        children.sort();
+        for child in children {
+            collect(&child, entries)?;
+        }
+    } else if metadata.is_file() {
+        entries.push((EntryKind::File, path.to_path_buf()));
+    } else {
+        log::warn!("Skipping {}, only files and directories can be archived", path.display());
+    }
+    Ok(())
+}
+
+/// Turns a path on disk into the relative, '/'-separated path stored in the index.
+/// Anything that isn't a plain name (`/`, `.`, `..`) is dropped, like tar does.
+fn archive_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Turns a path from the index back into a relative path, refusing anything that would escape the output directory
+fn safe_path(path: &str) -> Result<PathBuf, ParseError> {
+    let path = PathBuf::from(path);
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(format!("Refusing to extract `{}`, it isn't a plain relative path", path.display()).into());
+    }
+    Ok(path)
+}
+
+/// Whether `data` starts like a .pka archive
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Reads the index at the end of the archive
+pub fn read_index(data: &[u8]) -> Result<Vec<Entry>, ParseError> {
+    let cut_off = || ParseError::from("Archive index was cut off");
+    let rest = data.strip_prefix(MAGIC).ok_or(ParseError::from("This is not a .pka archive"))?;
+    let (&version, _) = rest.split_first().ok_or(ParseError::from("Archive header was cut off"))?;
+    if version != VERSION {
+        return Err(format!("Unsupported .pka version {version}, expected {VERSION}").into());
+    }
+
+    let (_, trailer) = data.split_last_chunk::<8>().ok_or_else(cut_off)?;
+    let index_offset = u64::from_be_bytes(*trailer) as usize;
+    let mut index = data
+        .get(index_offset..data.len() - 8)
+        .ok_or(ParseError::from("Archive index lies outside of the archive"))?;
+
+    let count = read_varint(&mut index).ok_or_else(cut_off)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let (&kind, rest) = index.split_first().ok_or_else(cut_off)?;
+        index = rest;
+        let kind = match kind {
+            0 => EntryKind::File,
+            1 => EntryKind::Directory,
+            _ => return Err(format!("Found an unknown entry type {kind}").into()),
+        };
+
+        let path_len = read_varint(&mut index).ok_or_else(cut_off)? as usize;
+        if index.len() < path_len {
+            return Err(cut_off());
+        }
+        let (path, rest) = index.split_at(path_len);
+        index = rest;
+
+        let mut field = || read_varint(&mut index).map(|v| v as usize).ok_or_else(cut_off);
+        entries.push(Entry {
+            kind,
+            path: String::from_utf8_lossy(path).into_owned(),
+            size: field()?,
+            offset: field()?,
+            compressed_len: field()?,
+        });
+    }
+    Ok(entries)
+}
+
+/// Decodes a file member of the archive, checking it against the size in the index
+pub fn read_member(data: &[u8], entry: &Entry, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+    let member = entry
+        .offset
+        .checked_add(entry.compressed_len)
+        .and_then(|end| data.get(entry.offset..end))
+        .ok_or(ParseError::from("Archive member lies outside of the archive"))?;
+    let mut tokens = Tokens::new(vec![]);
+    tokens.limits = *limits;
+    let decoded = tokens.decompress(member.to_vec())?;
+    if decoded.len() != entry.size {
+        return Err(format!(
+            "{} decoded to {} bytes, but the index says {}",
+            entry.path,
+            decoded.len(),
+            entry.size
+        )
+        .into());
+    }
+    Ok(decoded)
+}
@@ -0,0 +1,101 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use colored::Colorize;
+
+use crate::{encoders::encoder::format_pipeline, process, stats::Report, utils::*};
+
+/*
+    Several inputs in one run: every file is compressed (or decompressed) on its own, exactly like a single file
+    would be, by a pool of worker threads that each grab the next file in line until there are none left.
+
+    The stages log every step, which turns into noise once a few files interleave, so main sets the logger up to
+    only let warnings and errors through, apart from this module. The summary at the end takes the place of the
+    single-file statistics.
+*/
+
+/// Processes every input on a thread pool, then logs a per-file and aggregate summary. Returns whether all succeeded.
+pub fn run(inputs: &[String], args: &Args) -> bool {
+    let time = SystemTime::now();
+    let workers = match args.jobs {
+        Some(jobs) => jobs as usize,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    }
+    .min(inputs.len());
+    log::info!("Working on {} files with {workers} threads", inputs.len());
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = inputs.get(i) else {
+                    break;
+                };
+                let result = process(input, args).map_err(|e| e.to_string());
+                // Straight to stderr, so failures show up even with -q
+                if let Err(e) = &result {
+                    eprintln!("{input}: {e}");
+                }
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    summarize(inputs, &results, time.elapsed().unwrap_or_default());
+    results.iter().all(|(_, result)| result.is_ok())
+}
+
+fn summarize(inputs: &[String], results: &[(usize, Result<Report, String>)], elapsed: Duration) {
+    let width = inputs.iter().map(|i| i.len()).max().unwrap_or(0).max(4);
+    let mut lines = vec![format!(
+        "{:<width$} {:>12} {:>12} {:>8} {:>9}  Pipeline",
+        "File", "Input", "Output", "Ratio", "Time"
+    )];
+
+    let (mut input_total, mut output_total, mut failed) = (0, 0, 0);
+    for (i, result) in results.iter() {
+        match result {
+            Ok(report) => {
+                input_total += report.input_size;
+                output_total += report.output_size;
+                lines.push(format!(
+                    "{:<width$} {:>12} {:>12} {:>7.2}% {:>8.2}s  {}",
+                    report.input,
+                    report.input_size,
+                    report.output_size,
+                    ratio(report.output_size, report.input_size),
+                    report.elapsed.as_secs_f64(),
+                    match report.pipeline.is_empty() {
+                        true => "stored".to_string(),
+                        false => format_pipeline(&report.pipeline),
+                    }
+                ));
+            }
+            Err(e) => {
+                failed += 1;
+                lines.push(format!("{:<width$} {} ({e})", inputs[*i], "FAILED".red().bold()));
+            }
+        }
+    }
+    lines.push(format!(
+        "{:<width$} {:>12} {:>12} {:>7.2}% {:>8.2}s  {} files, {failed} failed",
+        "total",
+        input_total,
+        output_total,
+        ratio(output_total, input_total),
+        elapsed.as_secs_f64(),
+        results.len()
+    ));
+
+    log::info!("\n\n    {}\n", lines.join("\n    "));
+}
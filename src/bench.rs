@@ -0,0 +1,207 @@
+use std::{error::Error, fmt::Display, fs, path::Path, str::FromStr, time::SystemTime};
+
+use colored::Colorize;
+
+use crate::{
+    encoders::encoder::{check_pipeline, default_pipeline, format_pipeline, level_preset, Encoding, Tokens},
+    utils::*,
+};
+
+/*
+    bench: runs a few pipelines over every file in a directory, to compare them (or one release with the next).
+
+    Every run compresses the file, decompresses the result and checks it against the original, one at a time
+    so the timings don't get in each other's way. Peak memory is the process' high water mark of resident memory
+    (VmHWM in /proc/self/status), which is reset before each run by writing 5 to /proc/self/clear_refs.
+    That only works on Linux, everywhere else it is left blank.
+
+    The table goes to stdout, and --csv writes the same rows (minus the totals) to a file, which is easy to diff.
+*/
+
+/// A pipeline to benchmark, given as a pipeline spec (like `-p`) or a level from 1 to 9
+#[derive(Clone, Debug)]
+pub struct BenchPipeline {
+    pub name: String,
+    pub pipeline: Vec<Encoding>,
+}
+
+impl FromStr for BenchPipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pipeline = match s.parse::<u8>() {
+            Ok(level @ 1..=9) => level_preset(level),
+            Ok(_) => return Err("Levels go from 1 to 9".to_string()),
+            Err(_) => s
+                .split_whitespace()
+                .map(Encoding::from_str)
+                .collect::<Result<Vec<Encoding>, _>>()?,
+        };
+        check_pipeline(&pipeline)?;
+        let name = match s.parse::<u8>() {
+            Ok(level) => format!("level {level}"),
+            Err(_) => format_pipeline(&pipeline),
+        };
+        Ok(Self { name, pipeline })
+    }
+}
+
+/// Result of a single pipeline on a single file
+struct Run {
+    file: String,
+    pipeline: String,
+    input_size: usize,
+    output_size: usize,
+    compress_secs: f64,
+    decompress_secs: f64,
+    /// In KiB, None if it couldn't be measured
+    peak_memory: Option<u64>,
+    round_trip: bool,
+}
+
+/// Megabytes (of input) per second
+fn throughput(size: usize, secs: f64) -> f64 {
+    size as f64 / 1_000_000.0 / secs.max(1e-9)
+}
+
+/// Benchmarks `pipelines` (or a default selection) on every file under `dir`
+pub fn run(dir: &str, pipelines: &[BenchPipeline], csv: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let pipelines = match pipelines.is_empty() {
+        false => pipelines.to_vec(),
+        true => vec![
+            BenchPipeline {
+                name: "default".to_string(),
+                pipeline: default_pipeline(),
+            },
+            "1".parse()?,
+            "9".parse()?,
+        ],
+    };
+    // Rows and totals are told apart by name
+    for (i, bench) in pipelines.iter().enumerate() {
+        if pipelines[..i].iter().any(|other| other.name == bench.name) {
+            return Err(format!("`{}` is given more than once", bench.name).into());
+        }
+    }
+    let files = walk_files(Path::new(dir))?;
+
+    let mut runs = vec![];
+    for file in files.iter() {
+        let data = fs::read(file)?;
+        for bench in pipelines.iter() {
+            let reset = reset_peak_memory();
+
+            let time = SystemTime::now();
+            let compressed = Tokens::new(bench.pipeline.clone()).compress(data.clone());
+            let compress_secs = time.elapsed().unwrap_or_default().as_secs_f64();
+
+            let time = SystemTime::now();
+            let decoded = Tokens::new(vec![]).decompress(compressed.clone());
+            let decompress_secs = time.elapsed().unwrap_or_default().as_secs_f64();
+
+            runs.push(Run {
+                file: file.to_string_lossy().into_owned(),
+                pipeline: bench.name.clone(),
+                input_size: data.len(),
+                output_size: compressed.len(),
+                compress_secs,
+                decompress_secs,
+                // Without a reset, the peak would be the highest of the whole process so far
+                peak_memory: reset.then(peak_memory).flatten(),
+                round_trip: decoded.is_ok_and(|decoded| decoded == data),
+            });
+        }
+    }
+    print_table(&runs, &pipelines);
+    if let Some(csv) = csv {
+        fs::write(csv, to_csv(&runs))?;
+    }
+
+    if runs.iter().any(|run| !run.round_trip) {
+        return Err("Some files didn't decode back to the original".into());
+    }
+    Ok(())
+}
+
+/// Resets the high water mark of resident memory, so the next reading only covers what comes after.
+/// Returns whether it could.
+fn reset_peak_memory() -> bool {
+    fs::write("/proc/self/clear_refs", "5").is_ok()
+}
+
+/// Peak resident memory in KiB since the last reset
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn or_blank<T: Display>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn print_table(runs: &[Run], pipelines: &[BenchPipeline]) {
+    let file_width = runs.iter().map(|r| r.file.len()).max().unwrap_or(0).max(5);
+    let pipeline_width = runs.iter().map(|r| r.pipeline.len()).max().unwrap_or(0).max(8);
+
+    println!(
+        "{:<file_width$}  {:<pipeline_width$} {:>10} {:>10} {:>8} {:>10} {:>10} {:>10}  Round trip",
+        "File", "Pipeline", "Input", "Output", "Ratio", "Comp MB/s", "Dec MB/s", "Peak KiB"
+    );
+    let row = |run: &Run| {
+        println!(
+            "{:<file_width$}  {:<pipeline_width$} {:>10} {:>10} {:>7.2}% {:>10.2} {:>10.2} {:>10}  {}",
+            run.file,
+            run.pipeline,
+            run.input_size,
+            run.output_size,
+            ratio(run.output_size, run.input_size),
+            throughput(run.input_size, run.compress_secs),
+            throughput(run.input_size, run.decompress_secs),
+            or_blank(run.peak_memory),
+            match run.round_trip {
+                true => "ok".green(),
+                false => "FAILED".red().bold(),
+            }
+        )
+    };
+    runs.iter().for_each(row);
+
+    // One total per pipeline, over every file
+    println!();
+    for bench in pipelines {
+        let runs: Vec<&Run> = runs.iter().filter(|r| r.pipeline == bench.name).collect();
+        row(&Run {
+            file: "total".to_string(),
+            pipeline: bench.name.clone(),
+            input_size: runs.iter().map(|r| r.input_size).sum(),
+            output_size: runs.iter().map(|r| r.output_size).sum(),
+            compress_secs: runs.iter().map(|r| r.compress_secs).sum(),
+            decompress_secs: runs.iter().map(|r| r.decompress_secs).sum(),
+            peak_memory: runs.iter().filter_map(|r| r.peak_memory).max(),
+            round_trip: runs.iter().all(|r| r.round_trip),
+        });
+    }
+}
+
+fn to_csv(runs: &[Run]) -> String {
+    let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+    let mut csv = String::from(
+        "file,pipeline,input_size,output_size,ratio,compress_mb_s,decompress_mb_s,peak_memory_kib,round_trip\n",
+    );
+    for run in runs {
+        csv.push_str(&format!(
+            "{},{},{},{},{:.4},{:.3},{:.3},{},{}\n",
+            quote(&run.file),
+            quote(&run.pipeline),
+            run.input_size,
+            run.output_size,
+            ratio(run.output_size, run.input_size),
+            throughput(run.input_size, run.compress_secs),
+            throughput(run.input_size, run.decompress_secs),
+            or_blank(run.peak_memory),
+            run.round_trip
+        ));
+    }
+    csv
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, SystemTime};
+
+use super::{
+    encoder::{format_pipeline, level_preset, Encoding, Tokens},
+    mtf::MtfVariant,
+};
+
+/*
+    Automatic pipeline search, for when it isn't obvious which pipeline suits the data.
+
+    Compressing the whole input with every candidate would take forever, so every candidate only compresses
+    a few samples spread across the input, and the output size is extrapolated from there.
+    Candidates are tried in order until the time budget runs out (the first one always runs).
+    The ones without a BWT hardly make a dent in the budget, so they go first, followed by the most likely winners.
+
+    The samples are compressed one by one rather than glued together, since a seam in the middle
+    of a sample would throw off the BWT.
+*/
+
+// Size of each sample of the input
+const SAMPLE_SIZE: usize = 64_000;
+
+// Number of samples taken from inputs that are too big to try as a whole
+const SAMPLE_COUNT: usize = 3;
+
+/// The pipelines `--auto` picks from, in the order they are tried
+pub fn candidates() -> Vec<Vec<Encoding>> {
+    use Encoding::*;
+    let bwt = |pre: &[Encoding], variant| {
+        let mut pipeline = pre.to_vec();
+        pipeline.extend([
+            Bwt { block: 900_000 },
+            Mtf(variant),
+            Rle,
+            Huff { tables: 3 },
+        ]);
+        pipeline
+    };
+
+    vec![
+        vec![Huff { tables: 1 }],
+        level_preset(1),
+        vec![Delta { stride: 1 }, Rle, Huff { tables: 3 }],
+        level_preset(9),
+        bwt(&[], MtfVariant::Wfc),
+        vec![Bwt { block: 900_000 }, If, Huff { tables: 3 }],
+        bwt(&[Delta { stride: 1 }], MtfVariant::Mtf2),
+        bwt(&[Delta { stride: 2 }], MtfVariant::Mtf2),
+        bwt(&[Delta { stride: 4 }], MtfVariant::Mtf2),
+    ]
+}
+
+/// Picks out up to SAMPLE_COUNT evenly spaced samples of `input`, or all of it if it is small enough
+pub fn samples(input: &[u8]) -> Vec<&[u8]> {
+    if input.len() <= SAMPLE_SIZE * SAMPLE_COUNT {
+        return vec![input];
+    }
+    let step = (input.len() - SAMPLE_SIZE) / (SAMPLE_COUNT - 1);
+    (0..SAMPLE_COUNT)
+        .map(|i| &input[i * step..i * step + SAMPLE_SIZE])
+        .collect()
+}
+
+/// Estimates the size of `input` compressed with `pipeline`, by compressing its samples
+pub fn estimate(input: &[u8], pipeline: &[Encoding]) -> usize {
+    let samples = samples(input);
+    let sampled: usize = samples.iter().map(|s| s.len()).sum();
+    let compressed: usize = samples
+        .iter()
+        .map(|sample| Tokens::new(pipeline.to_vec()).compress(sample.to_vec()).len())
+        .sum();
+
+    match sampled {
+        0 => compressed,
+        _ => (compressed as f64 * input.len() as f64 / sampled as f64) as usize,
+    }
+}
+
+/// Tries the candidate pipelines on samples of `input`, returning the one with the smallest estimated output
+pub fn search(input: &[u8], budget: Duration) -> Vec<Encoding> {
+    let time = SystemTime::now();
+    let mut best: Option<(usize, Vec<Encoding>)> = None;
+    for pipeline in candidates() {
+        if best.is_some() && time.elapsed().unwrap_or_default() >= budget {
+            log::info!("Auto: Ran out of time, skipping the remaining pipelines");
+            break;
+        }
+
+        let estimate = estimate(input, &pipeline);
+        log::info!(
+            "Auto: {} => ~{estimate} bytes",
+            format_pipeline(&pipeline)
+        );
+
+        if best.as_ref().is_none_or(|(size, _)| estimate < *size) {
+            best = Some((estimate, pipeline));
+        }
+    }
+
+    let (estimate, pipeline) = best.expect("Expected at least one candidate pipeline");
+    log::info!(
+        "Auto: Picked {} (~{estimate} bytes) after {} ms",
+        format_pipeline(&pipeline),
+        time.elapsed().unwrap_or_default().as_millis()
+    );
+    pipeline
+}
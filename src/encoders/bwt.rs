@@ -1,13 +1,15 @@
 use std::cmp::Ordering;
-use std::fmt::{Debug, Display};
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::time::SystemTime;
-use std::{collections::HashMap, error::Error};
 
 use suffix_array::SuffixArray;
 
 use crate::utils::*;
 
-#[derive(PartialEq, Eq, PartialOrd, Hash, Clone, Copy)]
+use super::{encoder::ParseError, limits::DecodeLimits};
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 enum BwtToken {
     Delim,
     Byte(u8),
@@ -22,6 +24,12 @@ impl Debug for BwtToken {
     }
 }
 
+impl PartialOrd for BwtToken {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Ord for BwtToken {
     fn cmp(&self, other: &Self) -> Ordering {
         match (self, other) {
@@ -33,22 +41,54 @@ impl Ord for BwtToken {
     }
 }
 
-#[derive(Debug)]
-pub struct ParseError<'a>(&'a str);
+pub struct Bwt;
 
-impl<'a> Error for ParseError<'_> {}
+/// Marks a stream that was split into multiple blocks. Single-block streams start with a base-36 digit.
+const BLOCKED_MARKER: u8 = b'#';
 
-impl<'a> Display for ParseError<'a> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("Error while parsing data. File may be corrupted.\n")?;
-        f.write_str(&self.0)
-    }
-}
-
-pub struct Bwt;
+// Roughly what `inverse` allocates per byte of a block at its peak: the block as tokens twice over,
+// both of those enumerated, and the map between them (with room to spare, hash maps grow in powers of 2)
+const INVERSE_BYTES_PER_SYMBOL: usize = 100;
 
 impl Bwt {
-    pub fn encode(input: Vec<u8>) -> Vec<u8> {
+    /// Transforms `input`, splitting it into blocks of `block` bytes first (0 = one single block)
+    pub fn encode(input: Vec<u8>, block: usize) -> Vec<u8> {
+        if block == 0 || input.len() <= block {
+            let (delim_pos, mut encoded_output) = Self::transform(&input);
+
+            let delim_pos_b36 = format_radix(delim_pos as u32, 36);
+
+            log::info!(
+                "Encoding: Placing delim at position {delim_pos} (base 36) = {} (decimal)",
+                delim_pos_b36
+            );
+
+            let mut output = format!("{}|", delim_pos_b36).into_bytes();
+            output.append(&mut encoded_output);
+            return output;
+        }
+
+        // Blocked layout: [#] [varint block size] [varint total length] ([varint delim position] [block])...
+        // Every block transforms to exactly as many bytes as it had, so its length is implied
+        log::info!(
+            "Encoding: Splitting {} bytes into {} blocks of up to {block} bytes",
+            input.len(),
+            input.len().div_ceil(block)
+        );
+
+        let mut output = vec![BLOCKED_MARKER];
+        push_varint(&mut output, block as u64);
+        push_varint(&mut output, input.len() as u64);
+        for chunk in input.chunks(block) {
+            let (delim_pos, encoded_output) = Self::transform(chunk);
+            push_varint(&mut output, delim_pos as u64);
+            output.extend(encoded_output);
+        }
+        output
+    }
+
+    /// Returns the position of the delim, along with the transformed bytes (which skip the delim)
+    fn transform(input: &[u8]) -> (usize, Vec<u8>) {
         let time = SystemTime::now();
 
         // let mut tokens: Vec<Token> = input.iter().map(|&b| Token::Byte(b)).collect();
@@ -67,7 +107,8 @@ impl Bwt {
         //     .map(|(index, _token)| index)
         //     .collect();
 
-        let suffix_array: Vec<u32> = SuffixArray::new(&input).into_parts().1;
+
+        let suffix_array: Vec<u32> = SuffixArray::new(input).into_parts().1;
 
         let elapsed = time.elapsed().unwrap();
         log::info!(
@@ -76,7 +117,7 @@ impl Bwt {
         );
 
         let mut delim_pos: usize = 0;
-        let mut encoded_output: Vec<u8> = vec![];
+        let mut encoded_output: Vec<u8> = Vec::with_capacity(input.len());
 
         for (index, position) in suffix_array.iter().enumerate() {
             if *position > 0 {
@@ -86,33 +127,95 @@ impl Bwt {
             }
         }
 
-        let delim_pos_b36 = format_radix(delim_pos as u32, 36);
+        (delim_pos, encoded_output)
+    }
 
-        log::info!(
-            "Encoding: Placing delim at position {delim_pos} (base 36) = {} (decimal)",
-            delim_pos_b36
-        );
+    pub fn decode(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        if input.first() == Some(&BLOCKED_MARKER) {
+            let mut rest = &input[1..];
+            let block = read_varint(&mut rest).ok_or(ParseError::from("BWT header was cut off"))? as usize;
+            let total = read_varint(&mut rest).ok_or(ParseError::from("BWT header was cut off"))? as usize;
+            if block == 0 {
+                return Err(ParseError::from("Found a BWT block size of 0"));
+            }
 
-        let mut output = format!("{}|", delim_pos_b36).into_bytes();
-        output.append(&mut encoded_output);
-        output
-    }
+            log::info!("Decoding: Found {} blocks of up to {block} bytes", total.div_ceil(block));
+            limits.check("BWT", total, 1)?;
+
+            let mut output = vec![];
+            while output.len() < total {
+                let delim_pos =
+                    read_varint(&mut rest).ok_or(ParseError::from("BWT block header was cut off"))? as usize;
+                let len = block.min(total - output.len());
+                if rest.len() < len {
+                    return Err(ParseError::from("BWT block was cut off"));
+                }
+                let (data, next) = rest.split_at(len);
+                let decoded = Self::inverse(delim_pos, data, limits)?;
+                if decoded.len() != len {
+                    return Err(ParseError::from("BWT block decoded to the wrong length"));
+                }
+                output.extend(decoded);
+                rest = next;
+            }
+            return Ok(output);
+        }
 
-    pub fn decode(input: Vec<u8>) -> Vec<u8> {
         // First start by splitting on the first b'|', which separates the header & the data
         let split_index = input
             .iter()
             .position(|&b| b == b'|')
-            .expect("Unable to find BWT delimiter '|'");
+            .ok_or(ParseError::from("Unable to find BWT delimiter '|'"))?;
 
         let (header, data) = input.split_at(split_index);
         let header: String = header.iter().map(|b| char::from(*b)).collect();
-        let data = data.get(1..).expect("Unable to split bytes at '|'");
+        let data = &data[1..];
 
         let delim_pos = usize::from_str_radix(&header, 36)
-            .expect(format!("Unable to parse `{header}` into a b36 number").as_str());
+            .map_err(|_| ParseError::from(format!("Unable to parse `{header}` into a b36 number")))?;
 
         log::info!("Decoding: Placing delim at {delim_pos}");
+        Self::inverse(delim_pos, data, limits)
+    }
+
+    /// Reads the header fields of an encoded stream, for --list
+    pub fn describe(input: &[u8]) -> Option<Vec<(&'static str, String)>> {
+        if input.first() == Some(&BLOCKED_MARKER) {
+            let mut rest = &input[1..];
+            let block = read_varint(&mut rest)? as usize;
+            let total = read_varint(&mut rest)? as usize;
+            if block == 0 {
+                return None;
+            }
+
+            // Every block is its primary index followed by the block itself
+            let mut primary_indexes = vec![];
+            let mut remaining = total;
+            while remaining > 0 {
+                primary_indexes.push(read_varint(&mut rest)?.to_string());
+                rest = rest.get(block.min(remaining)..)?;
+                remaining -= block.min(remaining);
+            }
+            return Some(vec![
+                ("blocks", primary_indexes.len().to_string()),
+                ("block size", block.to_string()),
+                ("primary indexes", primary_indexes.join(",")),
+            ]);
+        }
+
+        let split_index = input.iter().position(|&b| b == b'|')?;
+        let header = std::str::from_utf8(&input[..split_index]).ok()?;
+        let primary_index = usize::from_str_radix(header, 36).ok()?;
+        Some(vec![("primary index", primary_index.to_string())])
+    }
+
+    /// Reverses the transform of a single block, given where its delim goes
+    fn inverse(delim_pos: usize, data: &[u8], limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        if delim_pos > data.len() {
+            return Err(ParseError::from("BWT delim position points past the end of the block"));
+        }
+        limits.check("BWT", data.len(), INVERSE_BYTES_PER_SYMBOL)?;
+
         // Convert all bytes to Tokens & insert the Delim based on header
         let mut tokens: Vec<BwtToken> = data.iter().map(|&b| BwtToken::Byte(b)).collect();
         tokens.insert(delim_pos, BwtToken::Delim);
@@ -133,11 +236,11 @@ impl Bwt {
 
         let mut map: HashMap<(BwtToken, usize), (BwtToken, usize)> = HashMap::new();
         sorted.iter().zip(&unsorted).for_each(|(p1, p2)| {
-            map.insert(p1.clone(), p2.clone());
+            map.insert(*p1, *p2);
         });
 
         let mut decoded_tokens: Vec<BwtToken> = Vec::with_capacity(unsorted.len());
-        let mut current_byte = (BwtToken::Delim, 0 as usize);
+        let mut current_byte = (BwtToken::Delim, 0_usize);
 
         drop(sorted);
         drop(unsorted);
@@ -146,7 +249,7 @@ impl Bwt {
         while *decoded_tokens.last().unwrap_or(&BwtToken::Byte(0)) != BwtToken::Delim {
             let next_byte = map
                 .remove(&current_byte)
-                .expect("A byte was read that was really not supposed to be there...");
+                .ok_or(ParseError::from("A byte was read that was really not supposed to be there..."))?;
             decoded_tokens.push(next_byte.0);
             current_byte = next_byte;
         }
@@ -160,6 +263,6 @@ impl Bwt {
             })
             .collect();
 
-        output
+        Ok(output)
     }
 }
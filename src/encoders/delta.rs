@@ -0,0 +1,43 @@
+/*
+    Delta coding replaces every byte with its difference (mod 256) from the byte `stride` positions before it.
+    The first `stride` bytes are kept as-is.
+
+    This does nothing for text, but turns slowly changing binary data (audio samples, tables of integers,
+    pixel rows...) into lots of small values, which the later stages can then squeeze.
+    For example, a table of little-endian u32's usually wants a stride of 4.
+
+    The stride isn't written into the stream, since it is stored with the pipeline in the .pkz header.
+    A stride of 0 would compare every byte with itself and zero the data, so it is never valid.
+*/
+
+use super::encoder::ParseError;
+
+pub struct Delta;
+
+impl Delta {
+    /// Panics if `stride` is 0, which `Encoding::from_str` never produces
+    pub fn encode(input: Vec<u8>, stride: u8) -> Vec<u8> {
+        assert!(stride > 0, "A delta stride must be at least 1");
+        let stride = stride as usize;
+        log::info!("Encoding: Using a stride of {stride}");
+
+        let mut output = input.clone();
+        for i in stride..input.len() {
+            output[i] = input[i].wrapping_sub(input[i - stride]);
+        }
+        output
+    }
+
+    pub fn decode(mut input: Vec<u8>, stride: u8) -> Result<Vec<u8>, ParseError> {
+        if stride == 0 {
+            return Err("A delta stride of 0 can't be decoded".into());
+        }
+        let stride = stride as usize;
+        log::info!("Decoding: Using a stride of {stride}");
+
+        for i in stride..input.len() {
+            input[i] = input[i].wrapping_add(input[i - stride]);
+        }
+        Ok(input)
+    }
+}
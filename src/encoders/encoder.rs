@@ -1,114 +1,1136 @@
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::Display,
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
 use colored::Colorize;
 
-use super::{bwt::Bwt, huff::Huff, mtf::Mtf, rle::Rle};
+use crate::utils::*;
+
+use super::{
+    bwt::Bwt,
+    delta::Delta,
+    huff::Huff,
+    inv_freq::If,
+    limits::DecodeLimits,
+    mtf::{Mtf, MtfVariant},
+    rle::Rle,
+    rle1::Rle1,
+};
+
+/*
+    Container layout:
+
+    "PKZ" [version] [flags] [file info] [number of stages] ([stage id] [params length] [params])... [block map] [stored blocks] [pipeline output]
+
+    Stage params are stored so decoding reproduces exactly what encoding did, without being told on the command line.
+
+    The flags say which parts of the file info are there, in this order (like gzip's -N):
+    the original file name (varint length + bytes), its Unix mode (varint), its mtime (varint seconds since the epoch)
+    and its size (varint), so --list can show the ratio without decoding anything.
+    Last comes a CRC-32 of the original data (4 bytes, big endian), which decoding and -t check against.
+
+    When the pipeline can't shrink the input, it is "stored" instead: written with a pipeline of 0 stages,
+    which decodes by doing nothing at all. That costs 11 bytes (magic, version, flags, CRC-32, stage count and
+    an empty block map) plus the varint size, and the name, mode and mtime unless they were left out with -n.
+
+    Mixed inputs (like a tarball with a few JPEGs in it) are scanned in blocks first, and blocks that look
+    incompressible skip the pipeline and are stored verbatim, so no time is wasted on them.
+    The block map is a single 0 byte if no block was stored, otherwise it is
+    [varint block size] [varint input length] [bitmask of stored blocks], followed by those blocks.
+    Everything else is glued back together and goes through the pipeline as usual.
+
+    Version 3 files are the same, minus the flags and file info. Version 2 files also lack the block map.
+
+    The original layout was just the stage ids followed by a '|'. Stage ids started at 0 and '|' is 0x7C,
+    so a legacy file can never start with "PKZ", and those still decode (with default params).
+*/
+const MAGIC: &[u8; 3] = b"PKZ";
+const VERSION: u8 = 4;
+
+// Flags for which parts of the file info are stored
+const FLAG_NAME: u8 = 1;
+const FLAG_MODE: u8 = 1 << 1;
+const FLAG_MTIME: u8 = 1 << 2;
+const FLAG_SIZE: u8 = 1 << 3;
+const FLAG_CHECKSUM: u8 = 1 << 4;
+
+// Size of the blocks the input is scanned in for incompressible data
+const STORE_BLOCK_SIZE: usize = 64_000;
+
+// Blocks with an order-0 entropy (in bits per byte) of at least this much are stored instead of compressed.
+// Text is usually around 4-5, and data that is already compressed sits right below 8.
+const STORE_ENTROPY: f64 = 7.9;
+
+// A stage whose output is more than 1/EXPANSION_LIMIT larger than the original input gives up on the pipeline.
+// Only the final output has to be smaller than the input, since stages in the middle grow the data on purpose:
+// BWT adds its indices, MTF its alphabet and IF a count per symbol (about 2% on text), which Huffman then wins back.
+// Giving up as soon as a stage grows the data at all would store nearly everything. Past a quarter, it's the data
+// itself growing rather than a header, which later stages won't win back.
+const EXPANSION_LIMIT: usize = 4;
+
+// Most stages a pipeline may have, since the Encoding Header counts them in a single byte
+pub const MAX_STAGES: usize = u8::MAX as usize;
+
+// Highest number of Huffman tables a pipeline may ask for (same as bzip2)
+const MAX_HUFF_TABLES: u8 = 6;
+
+/// Returned when decoding runs into data that doesn't make sense
+#[derive(Debug)]
+pub struct ParseError(Cow<'static, str>);
+
+impl ParseError {
+    /// What went wrong, without the line every error starts with
+    pub fn reason(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Error for ParseError {}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Error while parsing data. File may be corrupted.\n")?;
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&'static str> for ParseError {
+    fn from(message: &'static str) -> Self {
+        Self(Cow::Borrowed(message))
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(message: String) -> Self {
+        Self(Cow::Owned(message))
+    }
+}
+
+/// Keeps track of which blocks of the input skipped the pipeline
+#[derive(Clone, Debug, Default)]
+struct BlockMap {
+    block_size: usize,
+    input_len: usize,
+    /// One flag per block, true if it was stored. Empty if no block was stored.
+    stored: Vec<bool>,
+}
+
+impl BlockMap {
+    /// Flags every block of `input` that doesn't look worth compressing
+    fn scan(input: &[u8]) -> Self {
+        let stored: Vec<bool> = input
+            .chunks(STORE_BLOCK_SIZE)
+            .map(|block| order0_entropy(block) >= STORE_ENTROPY)
+            .collect();
+
+        Self {
+            block_size: STORE_BLOCK_SIZE,
+            input_len: input.len(),
+            stored: match stored.contains(&true) {
+                true => stored,
+                false => vec![],
+            },
+        }
+    }
+
+    fn stored_count(&self) -> usize {
+        self.stored.iter().filter(|&&s| s).count()
+    }
+
+    /// The length of every block, in order
+    fn block_lengths(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.stored.len()).map(|i| self.block_size.min(self.input_len - i * self.block_size))
+    }
+
+    /// Total length of the stored blocks
+    fn stored_len(&self) -> usize {
+        self.block_lengths()
+            .zip(self.stored.iter())
+            .filter(|(_, &s)| s)
+            .map(|(len, _)| len)
+            .sum()
+    }
+
+    /// Splits `input` into the stored blocks and the rest, which goes through the pipeline
+    fn split(&self, input: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        if self.stored.is_empty() {
+            return (vec![], input);
+        }
+        let (mut stored, mut rest) = (vec![], vec![]);
+        for (block, &is_stored) in input.chunks(self.block_size).zip(self.stored.iter()) {
+            match is_stored {
+                true => stored.extend_from_slice(block),
+                false => rest.extend_from_slice(block),
+            }
+        }
+        (stored, rest)
+    }
+
+    /// Puts the stored blocks and the decoded rest back in their original order
+    fn merge(&self, stored: &[u8], rest: Vec<u8>) -> Result<Vec<u8>, ParseError> {
+        if self.stored.is_empty() {
+            return Ok(rest);
+        }
+        // The input length comes from the header, so it has to match the data before anything is allocated for it
+        let coded_len = self.input_len - self.stored_len();
+        if stored.len() != self.stored_len() || rest.len() != coded_len {
+            return Err(format!(
+                "The block map says {} stored and {coded_len} coded bytes, but found {} and {}",
+                self.stored_len(),
+                stored.len(),
+                rest.len()
+            )
+            .into());
+        }
+        let (mut stored, mut rest) = (stored, rest.as_slice());
+        let mut output = Vec::with_capacity(self.input_len);
+        for (len, &is_stored) in self.block_lengths().zip(self.stored.iter()) {
+            let source = match is_stored {
+                true => &mut stored,
+                false => &mut rest,
+            };
+            if source.len() < len {
+                return Err(format!("Expected another block of {len} bytes, but the data was cut off").into());
+            }
+            let (block, next) = source.split_at(len);
+            output.extend_from_slice(block);
+            *source = next;
+        }
+        Ok(output)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut output = vec![];
+        if self.stored.is_empty() {
+            output.push(0);
+            return output;
+        }
+        push_varint(&mut output, self.block_size as u64);
+        push_varint(&mut output, self.input_len as u64);
+        let mut bitmask = vec![0u8; self.stored.len().div_ceil(8)];
+        for (i, _) in self.stored.iter().enumerate().filter(|(_, &s)| s) {
+            bitmask[i / 8] |= 1 << (i % 8);
+        }
+        output.extend(bitmask);
+        output
+    }
+
+    /// Reads back the output of `serialize`, advancing `data` past it
+    fn read(data: &mut &[u8]) -> Result<Self, ParseError> {
+        let cut_off = || ParseError::from("Block map was cut off");
+        let block_size = read_varint(data).ok_or_else(cut_off)? as usize;
+        if block_size == 0 {
+            return Ok(Self::default());
+        }
+        let input_len = read_varint(data).ok_or_else(cut_off)? as usize;
+        let blocks = input_len.div_ceil(block_size);
+        if data.len() < blocks.div_ceil(8) {
+            return Err(cut_off());
+        }
+        let (bitmask, rest) = data.split_at(blocks.div_ceil(8));
+        // Bits past the last block are never set, so anything there means the lengths don't match the bitmask
+        if !blocks.is_multiple_of(8) && bitmask[blocks / 8] >> (blocks % 8) != 0 {
+            return Err(ParseError::from("Block map flags more blocks than its lengths allow"));
+        }
+        *data = rest;
+        Ok(Self {
+            block_size,
+            input_len,
+            stored: (0..blocks).map(|i| bitmask[i / 8] & (1 << (i % 8)) != 0).collect(),
+        })
+    }
+}
+
+/// The original name, Unix mode, modification time and size of a compressed file. Anything missing isn't stored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileInfo {
+    pub name: Option<String>,
+    pub mode: Option<u32>,
+    /// Seconds since the Unix epoch
+    pub mtime: Option<u64>,
+    /// Always filled in when compressing
+    pub size: Option<u64>,
+    /// CRC-32 of the original data, always filled in when compressing
+    pub checksum: Option<u32>,
+}
+
+impl FileInfo {
+    fn serialize(&self) -> Vec<u8> {
+        let mut flags = 0;
+        let mut output = vec![];
+        if let Some(name) = &self.name {
+            flags |= FLAG_NAME;
+            push_varint(&mut output, name.len() as u64);
+            output.extend(name.as_bytes());
+        }
+        if let Some(mode) = self.mode {
+            flags |= FLAG_MODE;
+            push_varint(&mut output, mode as u64);
+        }
+        if let Some(mtime) = self.mtime {
+            flags |= FLAG_MTIME;
+            push_varint(&mut output, mtime);
+        }
+        if let Some(size) = self.size {
+            flags |= FLAG_SIZE;
+            push_varint(&mut output, size);
+        }
+        if let Some(checksum) = self.checksum {
+            flags |= FLAG_CHECKSUM;
+            output.extend(checksum.to_be_bytes());
+        }
+        output.insert(0, flags);
+        output
+    }
+
+    /// Reads back the output of `serialize`, advancing `data` past it
+    fn read(data: &mut &[u8]) -> Result<Self, ParseError> {
+        let (&flags, rest) = data.split_first().ok_or(ParseError::from("Encoding Header was cut off"))?;
+        *data = rest;
+        if flags & !(FLAG_NAME | FLAG_MODE | FLAG_MTIME | FLAG_SIZE | FLAG_CHECKSUM) != 0 {
+            return Err(format!("Found unknown header flags {flags:#010b}").into());
+        }
+
+        let mut info = Self::default();
+        if flags & FLAG_NAME != 0 {
+            let len = read_varint(data).ok_or(ParseError::from("File name was cut off"))? as usize;
+            if data.len() < len {
+                return Err("File name was cut off".into());
+            }
+            let (name, rest) = data.split_at(len);
+            info.name = Some(String::from_utf8_lossy(name).into_owned());
+            *data = rest;
+        }
+        if flags & FLAG_MODE != 0 {
+            info.mode = Some(read_varint(data).ok_or(ParseError::from("File mode was cut off"))? as u32);
+        }
+        if flags & FLAG_MTIME != 0 {
+            info.mtime = Some(read_varint(data).ok_or(ParseError::from("File mtime was cut off"))?);
+        }
+        if flags & FLAG_SIZE != 0 {
+            info.size = Some(read_varint(data).ok_or(ParseError::from("File size was cut off"))?);
+        }
+        if flags & FLAG_CHECKSUM != 0 {
+            let (checksum, rest) = data
+                .split_first_chunk::<4>()
+                .ok_or(ParseError::from("File checksum was cut off"))?;
+            info.checksum = Some(u32::from_be_bytes(*checksum));
+            *data = rest;
+        }
+        Ok(info)
+    }
+}
+
+/// Everything the container stores in front of the data
+#[derive(Clone, Debug, Default)]
+struct Header {
+    /// None for the original `[stage ids]|` layout
+    version: Option<u8>,
+    info: FileInfo,
+    pipeline: Vec<Encoding>,
+    blocks: BlockMap,
+}
+
+/// A stage as seen by `Tokens::inspect`
+#[derive(Clone, Debug)]
+pub struct StageInfo {
+    pub encoding: Encoding,
+    /// Length of what this stage wrote
+    pub output_len: usize,
+    /// Fields read from the header at the front of its output, None if it couldn't be read
+    pub fields: Option<Vec<(&'static str, String)>>,
+}
+
+/// What a .pkz file says about itself
+#[derive(Clone, Debug)]
+pub struct ContainerInfo {
+    /// None for the original `[stage ids]|` layout
+    pub version: Option<u8>,
+    pub info: FileInfo,
+    pub pipeline: Vec<Encoding>,
+    pub stored_blocks: usize,
+    pub total_blocks: usize,
+    /// In pipeline order
+    pub stages: Vec<StageInfo>,
+}
+
+/// How a stage did on its input
+#[derive(Clone, Debug)]
+pub struct StageStats {
+    pub encoding: Encoding,
+    pub input_len: usize,
+    pub output_len: usize,
+    pub elapsed: Duration,
+    /// Order-0 entropy in bits per byte, see `order0_entropy`
+    pub input_entropy: f64,
+    pub output_entropy: f64,
+}
+
+/// A stage that didn't decode back to its own input, found by `Tokens::paranoid`
+#[derive(Clone, Debug)]
+pub struct StageMismatch {
+    /// Position in the pipeline, from 1
+    pub stage: usize,
+    pub encoding: Encoding,
+    /// First offset where the decoded bytes differ from the stage's input, or why decoding failed
+    pub difference: Result<usize, String>,
+}
+
+impl Display for StageMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.difference {
+            Ok(offset) => write!(
+                f,
+                "Stage {} ({}) doesn't invert: its output decodes to something else from offset {offset} on",
+                self.stage, self.encoding
+            ),
+            Err(e) => write!(
+                f,
+                "Stage {} ({}) doesn't invert: its output fails to decode ({e})",
+                self.stage, self.encoding
+            ),
+        }
+    }
+}
+
+/// What a compress or decompress did, stage by stage
+#[derive(Clone, Debug, Default)]
+pub struct PipelineReport {
+    /// Every stage that ran, in the order they ran
+    pub stages: Vec<StageStats>,
+    /// Whether the pipeline's output was thrown away and the input stored as-is instead
+    pub stored: bool,
+    /// With `Tokens::paranoid`, the stage that didn't decode back to its input, which the pipeline stopped at
+    pub mismatch: Option<StageMismatch>,
+}
+
+impl PipelineReport {
+    /// The stages as a table, one line per stage
+    pub fn table(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{:<24} {:>10} {:>10} {:>8} {:>10}  Entropy (bits/byte)",
+            "Stage", "Input", "Output", "Ratio", "Time (ms)"
+        )];
+        for stage in self.stages.iter() {
+            lines.push(format!(
+                "{:<24} {:>10} {:>10} {:>7.2}% {:>10.2}  {:.3} -> {:.3}",
+                stage.encoding.to_string(),
+                stage.input_len,
+                stage.output_len,
+                ratio(stage.output_len, stage.input_len),
+                stage.elapsed.as_secs_f64() * 1000.0,
+                stage.input_entropy,
+                stage.output_entropy
+            ));
+        }
+        if self.stored {
+            lines.push("(stored as-is, without the pipeline)".to_string());
+        }
+        lines
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Tokens {
-    pipeline: Vec<Encoding>
+    pipeline: Vec<Encoding>,
+    /// Written to the header when compressing, and filled in from it when decompressing
+    pub info: FileInfo,
+    /// Where to write the bytes between every two stages, see `dump_stage`
+    pub dump_dir: Option<PathBuf>,
+    /// Decode every stage's output right after encoding it, and stop at the first one that doesn't give back
+    /// its input (see `PipelineReport::mismatch`). The input is then stored as-is, so the output is still valid.
+    pub paranoid: bool,
+    /// How much decompressing may allocate, see `DecodeLimits`. Unlimited unless set.
+    pub limits: DecodeLimits,
 }
 
-/// Defines the various types of Encoding Algorithms
+/// Defines the various types of Encoding Algorithms, along with their parameters
 #[allow(unused)]
-#[derive(Clone, Copy, Debug)]
-#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Encoding {
-    Bwt = 0,
-    Mtf = 1,
-    Rle = 2,
-    Huff = 3,
+    /// `block` = 0 transforms the whole input as a single block
+    Bwt { block: usize },
+    Mtf(MtfVariant),
+    Rle,
+    Huff { tables: u8 },
+    Rle1,
+    If,
+    Delta { stride: u8 },
 }
 
-impl TryFrom<u8> for Encoding {
-    type Error = ();
+impl Encoding {
+    /// The byte identifying this Encoding in the Encoding Header
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Bwt { .. } => 0,
+            Self::Mtf(_) => 1,
+            Self::Rle => 2,
+            Self::Huff { .. } => 3,
+            Self::Rle1 => 4,
+            Self::If => 5,
+            Self::Delta { .. } => 6,
+        }
+    }
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Self::Bwt),
-            1 => Ok(Self::Mtf),
-            2 => Ok(Self::Rle),
-            3 => Ok(Self::Huff),
-            _ => Err(()),
+    /// The stage's name, without its parameters
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Bwt { .. } => "bwt",
+            Self::Mtf(_) => "mtf",
+            Self::Rle => "rle",
+            Self::Huff { .. } => "huff",
+            Self::Rle1 => "rle1",
+            Self::If => "if",
+            Self::Delta { .. } => "delta",
         }
     }
+
+    /// Reads the header fields at the front of this stage's output, None if they can't be read
+    pub fn describe(&self, output: &[u8]) -> Option<Vec<(&'static str, String)>> {
+        match self {
+            Self::Bwt { .. } => Bwt::describe(output),
+            Self::Mtf(_) => Mtf::describe(output),
+            Self::Rle => Rle::describe(output),
+            Self::Huff { .. } => Huff::describe(output),
+            Self::If => If::describe(output),
+            Self::Rle1 | Self::Delta { .. } => Some(vec![]),
+        }
+    }
+
+    /// Serializes this Encoding's parameters for the Encoding Header
+    pub fn params(&self) -> Vec<u8> {
+        let mut params = vec![];
+        match self {
+            Self::Bwt { block } => push_varint(&mut params, *block as u64),
+            Self::Mtf(variant) => params.push(*variant as u8),
+            Self::Huff { tables } => params.push(*tables),
+            Self::Delta { stride } => params.push(*stride),
+            Self::Rle | Self::Rle1 | Self::If => {}
+        }
+        params
+    }
+
+    /// Rebuilds an Encoding from its id and serialized parameters
+    pub fn from_parts(id: u8, mut params: &[u8]) -> Option<Self> {
+        let encoding = match id {
+            0 => Self::Bwt {
+                block: read_varint(&mut params)? as usize,
+            },
+            1 => Self::Mtf(MtfVariant::try_from(*params.first()?).ok()?),
+            2 => Self::Rle,
+            3 => Self::Huff {
+                tables: *params.first().filter(|t| (1..=MAX_HUFF_TABLES).contains(t))?,
+            },
+            4 => Self::Rle1,
+            5 => Self::If,
+            6 => Self::Delta {
+                stride: *params.first().filter(|&&s| s > 0)?,
+            },
+            _ => return None,
+        };
+        Some(encoding)
+    }
+
+    /// Parses a single `key=value` parameter into this Encoding
+    fn set_param(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let name = self.to_string();
+        match (self, key) {
+            (Self::Bwt { block }, "block") => {
+                *block = parse_size(value)
+                    .filter(|&b| b > 0)
+                    .ok_or(format!("block must be a positive size like 900k, found `{value}`"))?;
+            }
+            (Self::Mtf(variant), "variant") => {
+                *variant = match value.to_lowercase().as_str() {
+                    "mtf" => MtfVariant::Mtf,
+                    "mtf1" | "mtf-1" => MtfVariant::Mtf1,
+                    "mtf2" | "mtf-2" => MtfVariant::Mtf2,
+                    "wfc" => MtfVariant::Wfc,
+                    _ => {
+                        return Err(format!(
+                            "variant must be one of mtf, mtf1, mtf2, wfc, found `{value}`"
+                        ))
+                    }
+                };
+            }
+            (Self::Huff { tables }, "tables") => {
+                *tables = value
+                    .parse()
+                    .ok()
+                    .filter(|t| (1..=MAX_HUFF_TABLES).contains(t))
+                    .ok_or(format!(
+                        "tables must be between 1 and {MAX_HUFF_TABLES}, found `{value}`"
+                    ))?;
+            }
+            (Self::Delta { stride }, "stride") => {
+                *stride = value
+                    .parse()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or(format!("stride must be between 1 and 255, found `{value}`"))?;
+            }
+            (_, key) => return Err(format!("`{name}` has no parameter `{key}`")),
+        }
+        Ok(())
+    }
+}
+
+/// Parses `name[:key=value[,key=value...]]`, for example `bwt:block=900k` or `mtf:variant=wfc`.
+/// `Mtf1`, `Mtf2` and `Wfc` are accepted as shorthands for the MTF variants.
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, params) = match s.split_once(':') {
+            Some((name, params)) => (name, Some(params)),
+            None => (s, None),
+        };
+
+        let mut encoding = match name.to_uppercase().as_str() {
+            "BWT" => Self::Bwt { block: 0 },
+            "MTF" => Self::Mtf(MtfVariant::Mtf),
+            "MTF1" => Self::Mtf(MtfVariant::Mtf1),
+            "MTF2" => Self::Mtf(MtfVariant::Mtf2),
+            "WFC" => Self::Mtf(MtfVariant::Wfc),
+            "RLE" => Self::Rle,
+            "HUFF" => Self::Huff { tables: 1 },
+            "RLE1" => Self::Rle1,
+            "IF" => Self::If,
+            "DELTA" => Self::Delta { stride: 1 },
+            _ => {
+                return Err(format!(
+                    "unknown stage `{name}`, expected one of Rle1 Delta Bwt Mtf Mtf1 Mtf2 Wfc If Rle Huff"
+                ))
+            }
+        };
+
+        for param in params.into_iter().flat_map(|p| p.split(',')) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or(format!("expected `key=value`, found `{param}`"))?;
+            encoding.set_param(&key.to_lowercase(), value)?;
+        }
+        Ok(encoding)
+    }
+}
+
+/// Formats in the same syntax `from_str` parses, leaving out default parameters
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bwt { block: 0 } => write!(f, "bwt"),
+            Self::Bwt { block } => write!(f, "bwt:block={}", format_size(*block)),
+            Self::Mtf(MtfVariant::Mtf) => write!(f, "mtf"),
+            Self::Mtf(variant) => write!(f, "mtf:variant={variant}"),
+            Self::Rle => write!(f, "rle"),
+            Self::Huff { tables: 1 } => write!(f, "huff"),
+            Self::Huff { tables } => write!(f, "huff:tables={tables}"),
+            Self::Rle1 => write!(f, "rle1"),
+            Self::If => write!(f, "if"),
+            Self::Delta { stride } => write!(f, "delta:stride={stride}"),
+        }
+    }
+}
+
+/// The pipeline used when neither a level nor a pipeline is given
+pub fn default_pipeline() -> Vec<Encoding> {
+    use Encoding::*;
+    vec![Bwt { block: 0 }, Mtf(MtfVariant::Mtf), Rle, Huff { tables: 1 }]
+}
+
+/// The preset pipeline for a compression level from 1 (fastest) to 9 (best).
+///
+/// Level 1 skips the BWT entirely. From there on, like bzip2, the BWT block grows by 100k per level,
+/// while the MTF variant and number of Huffman tables trade speed for a better fit.
+/// More than 3 tables rarely pays for its own header, so that's where the table count stops.
+pub fn level_preset(level: u8) -> Vec<Encoding> {
+    use Encoding::*;
+    let (variant, tables) = match level {
+        1 => return vec![Rle, Huff { tables: 1 }],
+        2 => (MtfVariant::Mtf, 1),
+        3 | 4 => (MtfVariant::Mtf, 2),
+        _ => (MtfVariant::Mtf2, 3),
+    };
+    vec![
+        Bwt {
+            block: level as usize * 100_000,
+        },
+        Mtf(variant),
+        Rle,
+        Huff { tables },
+    ]
+}
+
+/// Checks that a pipeline fits in the Encoding Header, which counts its stages in a single byte
+pub fn check_pipeline(pipeline: &[Encoding]) -> Result<(), String> {
+    if pipeline.len() > MAX_STAGES {
+        return Err(format!(
+            "A pipeline can have at most {MAX_STAGES} stages, found {}",
+            pipeline.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Formats a pipeline as a space-separated list, the same way `--pipeline` takes it
+pub fn format_pipeline(pipeline: &[Encoding]) -> String {
+    pipeline
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
 }
 
 impl Tokens {
     pub fn new(pipeline: Vec<Encoding>) -> Self {
-        Self { pipeline }
+        Self {
+            pipeline,
+            info: FileInfo::default(),
+            dump_dir: None,
+            paranoid: false,
+            limits: DecodeLimits::default(),
+        }
     }
 
+    /// Panics if the pipeline has more than `MAX_STAGES` stages, see `check_pipeline`
     pub fn compress(&mut self, data: Vec<u8>) -> Vec<u8> {
-        let mut output = data;
-        for encoder in self.pipeline.iter() {
+        self.compress_with_report(data).0
+    }
+
+    /// Same as `compress`, along with the size, time and entropy of every stage
+    pub fn compress_with_report(&mut self, data: Vec<u8>) -> (Vec<u8>, PipelineReport) {
+        let mut report = PipelineReport::default();
+        let output = self.compress_into(data, &mut report);
+        (output, report)
+    }
+
+    fn compress_into(&mut self, data: Vec<u8>, report: &mut PipelineReport) -> Vec<u8> {
+        self.info.size = Some(data.len() as u64);
+        self.info.checksum = Some(crc32(&data));
+        let blocks = BlockMap::scan(&data);
+        if blocks.stored_count() == blocks.stored.len() && !blocks.stored.is_empty() {
+            log::info!("Every block looks incompressible, storing the input as-is");
+            report.stored = true;
+            return self.store(data);
+        }
+        if !blocks.stored.is_empty() {
+            log::info!(
+                "Storing {} of {} blocks verbatim, their entropy is too high to compress",
+                blocks.stored_count(),
+                blocks.stored.len()
+            );
+        }
+
+        let original = data.clone();
+        let (stored, mut output) = blocks.split(data);
+        let input_len = output.len();
+        let mut entropy = order0_entropy(&output);
+        self.dump_stage("encode", 0, None, &output);
+        for (index, encoder) in self.pipeline.iter().enumerate() {
+            let time = SystemTime::now();
+            let stage_input_len = output.len();
+            let stage_input = self.paranoid.then(|| output.clone());
             match encoder {
-                Encoding::Bwt => {
+                Encoding::Bwt { block } => {
                     log::info!("=====[{} - BWT]=====", "ENCODE".green());
-                    output = Bwt::encode(output);
+                    output = Bwt::encode(output, *block);
                 }
                 Encoding::Rle => {
                     log::info!("=====[{} - RLE]=====", "ENCODE".green());
                     output = Rle::encode(output);
                 }
-                Encoding::Mtf => {
+                Encoding::Mtf(variant) => {
                     log::info!("=====[{} - MTF]=====", "ENCODE".green());
-                    output = Mtf::encode(output);
+                    output = Mtf::encode(output, *variant);
                 }
-                Encoding::Huff => {
+                Encoding::Huff { tables } => {
                     log::info!("=====[{} - HUFF]====", "ENCODE".green());
-                    output = Huff::encode(output);
+                    output = Huff::encode(output, *tables);
+                }
+                Encoding::Rle1 => {
+                    log::info!("=====[{} - RLE1]====", "ENCODE".green());
+                    output = Rle1::encode(output);
+                }
+                Encoding::If => {
+                    log::info!("=====[{} - IF]======", "ENCODE".green());
+                    output = If::encode(output);
+                }
+                Encoding::Delta { stride } => {
+                    log::info!("=====[{} - DELTA]===", "ENCODE".green());
+                    output = Delta::encode(output, *stride);
                 }
             };
+            let elapsed = time.elapsed().unwrap_or_default();
+            self.dump_stage("encode", index + 1, Some(encoder), &output);
+            let input_entropy = entropy;
+            entropy = order0_entropy(&output);
+            report.stages.push(StageStats {
+                encoding: *encoder,
+                input_len: stage_input_len,
+                output_len: output.len(),
+                elapsed,
+                input_entropy,
+                output_entropy: entropy,
+            });
+
+            if let Some(stage_input) = stage_input {
+                // Our own output, so there's no need to hold it to any limits
+                let difference = match Self::decode_stage(encoder, output.clone(), &DecodeLimits::UNLIMITED) {
+                    Ok(decoded) => first_difference(&stage_input, &decoded).map(Ok),
+                    Err(e) => Some(Err(e.to_string())),
+                };
+                if let Some(difference) = difference {
+                    let mismatch = StageMismatch {
+                        stage: index + 1,
+                        encoding: *encoder,
+                        difference,
+                    };
+                    log::error!("{mismatch}");
+                    report.mismatch = Some(mismatch);
+                    report.stored = true;
+                    return self.store(original);
+                }
+                log::info!("{encoder} decodes back to its input");
+            }
+
+            if output.len() > input_len + input_len / EXPANSION_LIMIT {
+                log::info!(
+                    "{encoder} grew the data to {} bytes (from {input_len}), giving up on the pipeline",
+                    output.len()
+                );
+                report.stored = true;
+                return self.store(original);
+            }
         }
 
-        let mut encoding_header: Vec<u8> = self.pipeline.iter().map(|&x| x as u8).collect();
-        encoding_header.push(b'|');
+        let mut encoding_header = self.header(&self.pipeline);
+        encoding_header.extend(blocks.serialize());
+        encoding_header.extend(stored);
+        if encoding_header.len() + output.len() > self.store(vec![]).len() + original.len() {
+            log::info!("Compressed output is larger than the input, storing it as-is instead");
+            report.stored = true;
+            return self.store(original);
+        }
         encoding_header.append(&mut output);
         encoding_header
     }
 
-    pub fn decompress(&mut self, data: Vec<u8>) -> Vec<u8> {
+    /*
+        --dump-stages: the bytes between every two stages, numbered so both directions line up.
+        `encode-02-mtf.bin` is what the 2nd stage (MTF) wrote, and `decode-02-mtf.bin` is what undoing the stages
+        after it gave back, so the two should be identical. Number 0 is the pipeline's input (or final output),
+        minus any blocks that were stored verbatim.
+    */
+    fn dump_stage(&self, direction: &str, index: usize, encoder: Option<&Encoding>, data: &[u8]) {
+        let Some(dir) = &self.dump_dir else {
+            return;
+        };
+        let name = encoder.map_or("input", |e| e.name());
+        let path = dir.join(format!("{direction}-{index:02}-{name}.bin"));
+        if let Err(e) = fs::write(&path, data) {
+            log::warn!("Couldn't write {}: {e}", path.display());
+        }
+    }
+
+    /// Wraps `data` in a header with an empty pipeline, which decodes to `data` itself
+    fn store(&self, data: Vec<u8>) -> Vec<u8> {
+        let mut output = self.header(&[]);
+        output.extend(BlockMap::default().serialize());
+        output.extend(data);
+        output
+    }
+
+    /// Serializes the Encoding Header for a pipeline
+    fn header(&self, pipeline: &[Encoding]) -> Vec<u8> {
+        let mut encoding_header: Vec<u8> = MAGIC.to_vec();
+        encoding_header.push(VERSION);
+        encoding_header.extend(self.info.serialize());
+        let stages = u8::try_from(pipeline.len()).expect("Pipelines are checked by check_pipeline");
+        encoding_header.push(stages);
+        for encoding in pipeline.iter() {
+            // At most a 10 byte varint
+            let params = encoding.params();
+            encoding_header.push(encoding.id());
+            encoding_header.push(params.len() as u8);
+            encoding_header.extend(params);
+        }
+        encoding_header
+    }
+
+    /// The pipeline to compress with, or the one read from the header after decompressing
+    pub fn pipeline(&self) -> &[Encoding] {
+        &self.pipeline
+    }
+
+    /// Decodes a .pkz container, checking the result against the size and checksum in its header
+    pub fn decompress(&mut self, data: Vec<u8>) -> Result<Vec<u8>, ParseError> {
+        Ok(self.decompress_with_report(data)?.0)
+    }
+
+    /// Same as `decompress`, along with the size, time and entropy of every stage
+    pub fn decompress_with_report(&mut self, data: Vec<u8>) -> Result<(Vec<u8>, PipelineReport), ParseError> {
+        let mut report = PipelineReport::default();
+        let (header, rest) = Self::read(&data)?;
+        let Header {
+            info,
+            pipeline,
+            blocks,
+            ..
+        } = header;
+        self.info = info;
+        self.pipeline = pipeline.clone();
+        // The size in the header isn't to be trusted either, but it does say early on when a file is too big
+        if let Some(size) = self.info.size {
+            self.limits
+                .check("This file", usize::try_from(size).unwrap_or(usize::MAX), 1)?;
+        }
+
+        if rest.len() < blocks.stored_len() {
+            return Err("Stored blocks were cut off".into());
+        }
+        let (stored, rest) = rest.split_at(blocks.stored_len());
+        let mut output = rest.to_vec();
+        if !blocks.stored.is_empty() {
+            log::info!(
+                "Found {} of {} blocks stored verbatim",
+                blocks.stored_count(),
+                blocks.stored.len()
+            );
+        }
+
+        match pipeline.is_empty() {
+            true => log::info!("Found a stored file, nothing to decode"),
+            false => log::info!("Found Encoding Pipeline: {}", format_pipeline(&pipeline)),
+        }
+
+        report.stored = pipeline.is_empty();
+        let mut entropy = order0_entropy(&output);
+        self.dump_stage("decode", pipeline.len(), pipeline.last(), &output);
+        for (index, encoder) in pipeline.iter().enumerate().rev() {
+            let time = SystemTime::now();
+            let input_len = output.len();
+            output = Self::decode_stage(encoder, output, &self.limits)?;
+            let elapsed = time.elapsed().unwrap_or_default();
+            self.dump_stage("decode", index, index.checked_sub(1).map(|i| &pipeline[i]), &output);
+            let input_entropy = entropy;
+            entropy = order0_entropy(&output);
+            report.stages.push(StageStats {
+                encoding: *encoder,
+                input_len,
+                output_len: output.len(),
+                elapsed,
+                input_entropy,
+                output_entropy: entropy,
+            });
+        }
+        if !blocks.stored.is_empty() {
+            self.limits.check("The block map", blocks.input_len, 1)?;
+        }
+        let output = blocks.merge(stored, output)?;
+
+        if let Some(size) = self.info.size {
+            if output.len() as u64 != size {
+                return Err(format!("Decoded {} bytes, but the header says {size}", output.len()).into());
+            }
+        }
+        if let Some(checksum) = self.info.checksum {
+            let actual = crc32(&output);
+            if actual != checksum {
+                return Err(format!("Checksum mismatch: expected {checksum:08x}, got {actual:08x}").into());
+            }
+        }
+        Ok((output, report))
+    }
+
+    /// Undoes a single stage
+    fn decode_stage(encoder: &Encoding, input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        match encoder {
+            Encoding::Bwt { .. } => {
+                log::info!("=====[{} - BWT]=====", "DECODE".cyan());
+                Bwt::decode(input, limits)
+            }
+            Encoding::Rle => {
+                log::info!("=====[{} - RLE]=====", "DECODE".cyan());
+                Rle::decode(input, limits)
+            }
+            Encoding::Mtf(_) => {
+                log::info!("=====[{} - MTF]=====", "DECODE".cyan());
+                Mtf::decode(input, limits)
+            }
+            Encoding::Huff { .. } => {
+                log::info!("=====[{} - HUFF]====", "DECODE".cyan());
+                Huff::decode(input, limits)
+            }
+            Encoding::Rle1 => {
+                log::info!("=====[{} - RLE1]====", "DECODE".cyan());
+                Rle1::decode(input, limits)
+            }
+            Encoding::If => {
+                log::info!("=====[{} - IF]======", "DECODE".cyan());
+                If::decode(input, limits)
+            }
+            Encoding::Delta { stride } => {
+                log::info!("=====[{} - DELTA]===", "DECODE".cyan());
+                Delta::decode(input, *stride)
+            }
+        }
+    }
+
+    /// Reads the headers of a .pkz file without decoding it all.
+    ///
+    /// Only the outermost stage's header can be read straight from the file, since every other one is wrapped
+    /// inside the stages after it. So the stages are peeled off one at a time, down to (but not including)
+    /// the innermost stage, which is usually the BWT and the slowest to undo.
+    /// A stage that can't be undone (or would go over `limits` doing so) leaves the fields of every stage
+    /// before it unreadable.
+    pub fn inspect(data: &[u8], limits: &DecodeLimits) -> Result<ContainerInfo, ParseError> {
+        let (header, rest) = Self::read(data)?;
+        let mut output = rest.get(header.blocks.stored_len()..).unwrap_or_default().to_vec();
+
+        let mut stages = vec![];
+        for (index, encoder) in header.pipeline.iter().enumerate().rev() {
+            stages.push(StageInfo {
+                encoding: *encoder,
+                output_len: output.len(),
+                fields: encoder.describe(&output),
+            });
+            if index > 0 {
+                output = Self::decode_stage(encoder, output, limits).unwrap_or_default();
+            }
+        }
+        stages.reverse();
+
+        Ok(ContainerInfo {
+            version: header.version,
+            info: header.info,
+            pipeline: header.pipeline,
+            stored_blocks: header.blocks.stored_count(),
+            total_blocks: header.blocks.stored.len(),
+            stages,
+        })
+    }
+
+    /// The bytes the stage at `index` in the pipeline wrote, found by undoing every stage after it
+    pub fn stage_output(data: &[u8], index: usize, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        let (header, rest) = Self::read(data)?;
+        let mut output = rest
+            .get(header.blocks.stored_len()..)
+            .ok_or(ParseError::from("Stored blocks were cut off"))?
+            .to_vec();
+        for encoder in header.pipeline.iter().skip(index + 1).rev() {
+            output = Self::decode_stage(encoder, output, limits)?;
+        }
+        Ok(output)
+    }
+
+    /// The pipeline of `data`, along with the bytes every stage wrote, from the outermost stage in.
+    /// Each stage is undone once, so this costs one decode no matter how many stages there are.
+    /// The list ends with the error of the first stage that can't be undone, as every stage before it is out of reach.
+    #[allow(clippy::type_complexity)]
+    pub fn stage_outputs(
+        data: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<(Vec<Encoding>, Vec<Result<Vec<u8>, ParseError>>), ParseError> {
+        let (header, rest) = Self::read(data)?;
+        let mut output = rest
+            .get(header.blocks.stored_len()..)
+            .ok_or(ParseError::from("Stored blocks were cut off"))?
+            .to_vec();
+        let mut outputs = vec![];
+        for (index, encoder) in header.pipeline.iter().enumerate().rev() {
+            outputs.push(Ok(output.clone()));
+            if index == 0 {
+                break;
+            }
+            match Self::decode_stage(encoder, output, limits) {
+                Ok(decoded) => output = decoded,
+                Err(e) => {
+                    outputs.push(Err(e));
+                    break;
+                }
+            }
+        }
+        Ok((header.pipeline, outputs))
+    }
+
+    /// Parses whichever header `data` starts with, returning it along with the rest of the data
+    fn read(data: &[u8]) -> Result<(Header, &[u8]), ParseError> {
+        match data.strip_prefix(MAGIC) {
+            Some(rest) => Self::read_header(rest),
+            None => Self::read_legacy_header(data),
+        }
+    }
+
+    /// Parses the Encoding Header that follows the magic bytes, returning it along with the rest of the data
+    fn read_header(data: &[u8]) -> Result<(Header, &[u8]), ParseError> {
+        let cut_off = || ParseError::from("Encoding Header was cut off");
+        let (&version, mut rest) = data.split_first().ok_or_else(cut_off)?;
+        if !(2..=VERSION).contains(&version) {
+            return Err(format!("Unsupported .pkz version {version}, expected {VERSION}").into());
+        }
+
+        let info = match version {
+            2 | 3 => FileInfo::default(),
+            _ => FileInfo::read(&mut rest)?,
+        };
+
+        let (&stage_count, mut rest) = rest.split_first().ok_or_else(cut_off)?;
+        let mut pipeline = Vec::with_capacity(stage_count as usize);
+        for _ in 0..stage_count {
+            let [id, params_len, ref next @ ..] = *rest else {
+                return Err(cut_off());
+            };
+            if next.len() < params_len as usize {
+                return Err(cut_off());
+            }
+            let (params, next) = next.split_at(params_len as usize);
+            pipeline.push(Encoding::from_parts(id, params).ok_or(ParseError::from("Found an unmapped Encoding"))?);
+            rest = next;
+        }
+
+        let blocks = match version {
+            2 => BlockMap::default(),
+            _ => BlockMap::read(&mut rest)?,
+        };
+        let header = Header {
+            version: Some(version),
+            info,
+            pipeline,
+            blocks,
+        };
+        Ok((header, rest))
+    }
+
+    /// Parses the original `[stage ids]|` Encoding Header
+    fn read_legacy_header(data: &[u8]) -> Result<(Header, &[u8]), ParseError> {
         let split_index = data
             .iter()
             .position(|&b| b == b'|')
-            .expect("Unable to find Encoding Header delimiter '|'");
+            .ok_or(ParseError::from("Unable to find Encoding Header delimiter '|'"))?;
 
-        
-        let mut encode_header = vec![0; split_index];
-        encode_header[..].clone_from_slice(&data.split_at(split_index).0);
-        let mut output = data;
-
-        output = output.strip_prefix(&encode_header[..]).unwrap().into();
-        output = output.strip_prefix(&[b'|']).unwrap().into();
+        let (encode_header, rest) = data.split_at(split_index);
 
         let pipeline: Vec<Encoding> = encode_header
             .iter()
-            .map(|x| {
-                Encoding::try_from(*x).expect("Found an unmapped Encoding Enum")
+            .map(|&x| match x {
+                0 => Ok(Encoding::Bwt { block: 0 }),
+                1 => Ok(Encoding::Mtf(MtfVariant::Mtf)),
+                2 => Ok(Encoding::Rle),
+                3 => Ok(Encoding::Huff { tables: 1 }),
+                _ => Err(ParseError::from("Found an unmapped Encoding Enum")),
             })
-            .collect();
+            .collect::<Result<_, _>>()?;
 
-        log::info!("Found Encoding Pipeline: {:?}", &pipeline);
-
-        for encoder in pipeline.iter().rev() {
-            match encoder {
-                Encoding::Bwt => {
-                    log::info!("=====[{} - BWT]=====", "DECODE".cyan());
-                    output = Bwt::decode(output);
-                }
-                Encoding::Rle => {
-                    log::info!("=====[{} - RLE]=====", "DECODE".cyan());
-                    output = Rle::decode(output);
-                }
-                Encoding::Mtf => {
-                    log::info!("=====[{} - MTF]=====", "DECODE".cyan());
-                    output = Mtf::decode(output);
-                }
-                Encoding::Huff => {
-                    log::info!("=====[{} - HUFF]====", "DECODE".cyan());
-                    output = Huff::decode(output);
-                }
-            };
-        }
-        return output;
+        let header = Header {
+            pipeline,
+            ..Default::default()
+        };
+        Ok((header, &rest[1..]))
     }
 }
@@ -1,108 +1,46 @@
-use std::collections::HashMap;
+use crate::*;
 
-use super::huff_helper::*;
+use super::{encoder::ParseError, huff_helper::*, limits::DecodeLimits};
 
 // Huffman Encoding
 
+// Number of symbols coded with the same table when using multiple tables (same as bzip2)
+const GROUP_SIZE: usize = 50;
+
+// Number of refinement passes when assigning groups to tables
+const TABLE_ITERATIONS: usize = 4;
+
+// Longest code allowed when using multiple tables (same as bzip2)
+const MAX_CODE_LENGTH: usize = 20;
+
 pub struct Huff;
 
 impl Huff {
-    pub fn encode(input: Vec<u8>) -> Vec<u8> {
-        if input.len() == 0 {
+    pub fn encode(input: Vec<u8>, tables: u8) -> Vec<u8> {
+        if input.is_empty() {
             return input;
         }
+        if tables > 1 {
+            return Self::encode_multi(input, tables);
+        }
 
         // Create an array of zeroes. A byte's frequency = freq_map[byte]
-        let freq_map: &mut [usize] = &mut [0; 256 as usize];
+        let mut freq_map = [0; 256];
         input.iter().for_each(|&byte| {
             freq_map[byte as usize] += 1;
         });
 
-        // Iterate through and construct HuffNodes for all non-zero frequency bytes
-        let mut freq_map: Vec<HuffmanNode> = freq_map
-            .iter()
-            .enumerate()
-            .filter_map(|(byte, &count)| match count > 0 {
-                true => Some(HuffmanNode {
-                    byte: Node::Leaf(byte as u8),
-                    frequency: count,
-                    left: None,
-                    right: None,
-                }),
-                false => None,
-            })
-            .collect();
-
-        // Huffman Tree creation:
-        // Pop smallest 2 frequencies
-        // Combine into 1 node
-        // Reinsert node
-        // Repeat until one node is left
-
-        let mut internal_count = 0;
-        while freq_map.len() > 1 {
-            freq_map.sort();
-            // Reverse so push and pop are zero-cost (least freq at end)
-            freq_map.reverse();
-
-            let n1 = freq_map.pop().expect("Expected to find a HuffNode");
-            let n2 = freq_map.pop().expect("Expected to find a HuffNode");
-
-            // When creating a parent node, convention is to place
-            // the smaller of the 2 children on the left
-            let combined_node = HuffmanNode {
-                byte: Node::Internal(internal_count),
-                frequency: n1.frequency + n2.frequency,
-                left: Some(Box::new(n1)),
-                right: Some(Box::new(n2)),
-            };
-
-            internal_count += 1;
-
-            // TODO: make this insertion to avoid resorting of array
-            freq_map.push(combined_node);
-        }
-
-        let root = freq_map
-            .pop()
-            .expect("Expected there to be more than 0 HuffNodes");
-        let root = Some(Box::new(root));
+        let root = build_huffman_tree(&freq_map);
 
         // Serialize the huffman tree
-
         let mut preorder = vec![];
         pre_order(&root, &mut preorder);
 
         let mut inorder = vec![];
         in_order(&root, &mut inorder);
 
-        let mut prebytes = vec![];
-        for node in preorder {
-            match node {
-                Node::Leaf(b) => {
-                    prebytes.push(1);
-                    prebytes.push(b);
-                }
-                Node::Internal(b) => {
-                    prebytes.push(0);
-                    prebytes.push(b);
-                }
-            }
-        }
-
-        let mut inbytes = vec![];
-        for node in inorder {
-            match node {
-                Node::Leaf(b) => {
-                    inbytes.push(1);
-                    inbytes.push(b);
-                }
-                Node::Internal(b) => {
-                    inbytes.push(0);
-                    inbytes.push(b);
-                }
-            }
-        }
+        let mut prebytes = serialize_nodes(&preorder);
+        let mut inbytes = serialize_nodes(&inorder);
 
         log::info!("Encoding: File is {} bytes long", input.len());
         log::info!("Encoding: Tree is {} bytes long", prebytes.len());
@@ -126,137 +64,319 @@ impl Huff {
         file_contents.append(&mut file_len);
 
         // Encode the actual data via Huffman Coding
-        // Retrieve seen paths from a HashMap, manually perform the traversal on a miss
         // The path will then be encoded into a byte, with up to
         // 7 extra bits being added as padding (all will be 0's)
-        let mut paths: HashMap<u8, Vec<u8>> = HashMap::new();
-        let mut output_data: Vec<u8> = vec![];
-        let mut current_byte: u8 = 0;
-        let mut num_bits: u8 = 0;
-
+        let paths = code_paths(&root);
+        let mut writer = BitWriter::new(file_contents);
         for &byte in input.iter() {
-            let path = match paths.get(&byte) {
-                Some(v) => v,
-                None => {
-                    let mut path = vec![];
-                    encode_byte(&root, byte, &mut path);
-                    path.reverse();
-                    paths.insert(byte, path);
-                    paths.get(&byte).unwrap()
-                }
-            };
+            writer.push_path(&paths[byte as usize]);
+        }
+        writer.finish()
+    }
 
-            for turn in path {
-                if num_bits > 7 {
-                    output_data.push(current_byte);
-                    current_byte = 0;
-                    num_bits = 0;
-                }
+    pub fn decode(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        Ok(Self::decode_with_trees(input, limits)?.0)
+    }
 
-                current_byte |= turn << (7 - num_bits);
-                num_bits += 1;
+    /// Rebuilds the tree (or one per table) of an encoded stream, exactly like `decode` does, with the frequency
+    /// of every node counted from the symbols it decodes
+    pub fn trees(input: &[u8], limits: &DecodeLimits) -> Result<Vec<Option<Box<HuffmanNode>>>, ParseError> {
+        let (output, mut roots, selectors) = Self::decode_with_trees(input.to_vec(), limits)?;
+
+        let mut counts = vec![[0usize; 256]; roots.len()];
+        match selectors.is_empty() {
+            true => output.iter().for_each(|&b| counts[0][b as usize] += 1),
+            false => {
+                for (group, &table) in output.chunks(GROUP_SIZE).zip(selectors.iter()) {
+                    group.iter().for_each(|&b| counts[table as usize][b as usize] += 1);
+                }
             }
         }
-        if num_bits > 0 {
-            output_data.push(current_byte);
+        for (root, counts) in roots.iter_mut().zip(counts.iter()) {
+            fill_frequencies(root, counts);
         }
+        Ok(roots)
+    }
+
+    /// Same as `decode`, along with the tree(s) it decoded with,
+    /// and the table picked by every group of GROUP_SIZE symbols (empty with a single table)
+    #[allow(clippy::type_complexity)]
+    fn decode_with_trees(
+        input: Vec<u8>,
+        limits: &DecodeLimits,
+    ) -> Result<(Vec<u8>, Vec<Option<Box<HuffmanNode>>>, Vec<u8>), ParseError> {
+        if input.is_empty() {
+            return Ok((input, vec![], vec![]));
+        }
+        // The single-table format starts with a u64 tree length, so its first byte is always 0
+        if input[0] != 0 {
+            return Self::decode_multi(input, limits);
+        }
+
+        let (root, tree_len, rest) = Self::read_tree(&input)?;
+        let file_len = u64::from_be_bytes(
+            rest.get(..8)
+                .ok_or("Huffman header was cut off")?
+                .try_into()
+                .unwrap(),
+        );
+        let data = &rest[8..];
 
-        file_contents.append(&mut output_data);
-        file_contents
+        log::info!("Decoding: File is {file_len} bytes long");
+        log::info!("Decoding: Tree is {tree_len} bytes long");
+        // A tree that is a single Leaf reads no bits at all, so nothing but this stops it from decoding forever
+        limits.check("Huffman", usize::try_from(file_len).unwrap_or(usize::MAX), 1)?;
+
+        let mut reader = BitReader::new(data);
+        let mut output_data = vec![];
+        while (output_data.len() as u64) < file_len {
+            output_data.push(decode_symbol(&root, &mut reader)?);
+        }
+        Ok((output_data, vec![root], vec![]))
     }
 
-    pub fn decode(input: Vec<u8>) -> Vec<u8> {
-        if input.len() == 0 {
-            return input;
+    /// Reads the tree at the start of a single-table stream, which is laid out as:
+    /// tree length: 8 bytes
+    /// preorder: tree_len bytes
+    /// inorder: tree_len bytes
+    /// file length: 8 bytes
+    /// data: rest of the file (we stop reading bits after file_len bits)
+    ///
+    /// Returns the tree, the tree length and everything after the tree (starting at the file length).
+    /// The tree length is checked before anything is built, since building the tree recurses once per level.
+    #[allow(clippy::type_complexity)]
+    fn read_tree(input: &[u8]) -> Result<(Option<Box<HuffmanNode>>, usize, &[u8]), ParseError> {
+        let cut_off = || ParseError::from("Huffman header was cut off");
+        let tree_len = u64::from_be_bytes(input.get(..8).ok_or_else(cut_off)?.try_into().unwrap());
+        // A tree has at most 511 nodes, 2 bytes each
+        let tree_len = usize::try_from(tree_len)
+            .ok()
+            .filter(|&len| len <= 2 * 511)
+            .ok_or(ParseError::from("Huffman tree is larger than any tree can be"))?;
+
+        let rest = &input[8..];
+        let preorder = rest.get(..tree_len).ok_or_else(cut_off)?;
+        let inorder = rest.get(tree_len..2 * tree_len).ok_or_else(cut_off)?;
+        let root = build_tree(&deserialize_nodes(preorder)?, &deserialize_nodes(inorder)?)?;
+        Ok((root, tree_len, &rest[2 * tree_len..]))
+    }
+
+    /// Reads the header fields of an encoded stream, for --list
+    pub fn describe(input: &[u8]) -> Option<Vec<(&'static str, String)>> {
+        let Some(&tables) = input.first() else {
+            return Some(vec![]);
+        };
+
+        if tables == 0 {
+            let (_, tree_len, rest) = Self::read_tree(input).ok()?;
+            let file_len = u64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+            return Some(vec![
+                ("tables", "1".to_string()),
+                ("tree size", format!("{} nodes", tree_len / 2)),
+                ("decoded length", file_len.to_string()),
+            ]);
         }
 
-        // Decode the header which contains the following in order:
-        // file length: 8 bytes
-        // tree length: 8 bytes
-        // preorder: tree_len bytes
-        // inorder: tree_len bytes
-        // data: rest of the file (we stop reading bits after file_len bits)
+        let mut rest = &input[1..];
+        let file_len = read_varint(&mut rest)?;
+        let symbols: u32 = rest.get(..32)?.iter().map(|b| b.count_ones()).sum();
+        Some(vec![
+            ("tables", tables.to_string()),
+            ("symbols", symbols.to_string()),
+            ("decoded length", file_len.to_string()),
+        ])
+    }
 
-        let (tree_len, rest) = input.split_at(8);
-        let tree_len = u64::from_be_bytes(tree_len.try_into().unwrap());
+    /// Reads the code length of every byte (0 = unused) from the header of an encoded stream, one array per table,
+    /// for `inspect`
+    pub fn code_lengths(input: &[u8]) -> Option<Vec<[u8; 256]>> {
+        let &tables = input.first()?;
 
-        let (preorder, rest) = rest.split_at(tree_len as usize);
-        let (inorder, rest) = rest.split_at(tree_len as usize);
+        if tables == 0 {
+            let (root, _, _) = Self::read_tree(input).ok()?;
+            let paths = code_paths(&root);
+            return Some(vec![std::array::from_fn(|b| paths[b].len() as u8)]);
+        }
 
-        let (file_len, data) = rest.split_at(8);
-        let file_len = u64::from_be_bytes(file_len.try_into().unwrap());
+        let mut rest = &input[1..];
+        read_varint(&mut rest)?;
+        let bitmask = rest.get(..32)?;
+        let used: Vec<usize> = (0..256)
+            .filter(|&b| bitmask[b / 8] & (1 << (b % 8)) != 0)
+            .collect();
+        let mut lengths = vec![];
+        for table in rest[32..].chunks(used.len().max(1)).take(tables as usize) {
+            let mut table_lengths = [0u8; 256];
+            used.iter().zip(table).for_each(|(&b, &len)| table_lengths[b] = len);
+            lengths.push(table_lengths);
+        }
+        Some(lengths)
+    }
 
-        log::info!("Decoding: File is {file_len} bytes long");
-        log::info!("Decoding: Tree is {tree_len} bytes long");
+    /*
+        With multiple tables, the input is split into groups of GROUP_SIZE symbols, and every group
+        picks whichever table codes it the cheapest (just like bzip2).
+        Tables start out as contiguous slices of the input, then get rebuilt from the groups that picked them
+        a few times over, so each table drifts towards one "kind" of data.
+
+        Storing several full trees would eat most of the gains, so tables are stored as code lengths
+        (canonical Huffman codes), limited to MAX_CODE_LENGTH bits.
+
+        Layout:
+        1. Number of tables (>= 2, which is how this format is told apart from the single-table one)
+        2. Expected length of decoded file, as a varint
+        3. Bitmask of the bytes used in the file (32 bytes)
+        4. For every table, one code length per used byte
+        5. Bitstream with one selector (table index) per group, then the encoded data of file
+
+        Neighbouring groups usually pick the same table, so like bzip2, selectors are MTF'd and written in unary
+        (rank r = r 1's followed by a 0), which usually costs a single bit per group.
+    */
+    fn encode_multi(input: Vec<u8>, tables: u8) -> Vec<u8> {
+        let groups: Vec<&[u8]> = input.chunks(GROUP_SIZE).collect();
+
+        // There's no point in having more tables than groups
+        let tables = (tables as usize).min(groups.len());
+        if tables < 2 {
+            return Self::encode(input, 1);
+        }
 
-        let mut pre_iter = preorder.iter();
-        let mut preorder = vec![];
-        while let Some(&byte) = pre_iter.next() {
-            match byte {
-                0 => {
-                    preorder.push(Node::Internal(*pre_iter.next().unwrap()));
-                }
-                1 => {
-                    preorder.push(Node::Leaf(*pre_iter.next().unwrap()));
-                }
-                _ => panic!("Found an unexpected byte"),
+        let mut used = [false; 256];
+        input.iter().for_each(|&b| used[b as usize] = true);
+
+        let mut selectors: Vec<u8> = (0..groups.len())
+            .map(|g| (g * tables / groups.len()) as u8)
+            .collect();
+        let mut lengths = vec![];
+
+        for iteration in 0..TABLE_ITERATIONS {
+            // Every used byte gets at least a frequency of 1, so any group can be coded with any table
+            let mut freqs = vec![[0usize; 256]; tables];
+            freqs.iter_mut().for_each(|f| {
+                (0..256).filter(|&b| used[b]).for_each(|b| f[b] = 1);
+            });
+            for (group, &table) in groups.iter().zip(selectors.iter()) {
+                group.iter().for_each(|&b| freqs[table as usize][b as usize] += 1);
             }
-        }
+            lengths = freqs.iter().map(|f| code_lengths(f, MAX_CODE_LENGTH)).collect();
 
-        let mut in_iter = inorder.iter();
-        let mut inorder = vec![];
-        while let Some(&byte) = in_iter.next() {
-            match byte {
-                0 => {
-                    inorder.push(Node::Internal(*in_iter.next().unwrap()));
-                }
-                1 => {
-                    inorder.push(Node::Leaf(*in_iter.next().unwrap()));
-                }
-                _ => panic!("Found an unexpected byte"),
+            if iteration == TABLE_ITERATIONS - 1 {
+                break;
+            }
+
+            // Reassign every group to its cheapest table
+            for (group, selector) in groups.iter().zip(selectors.iter_mut()) {
+                let (best, _) = lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(table, len)| {
+                        let cost: usize = group.iter().map(|&b| len[b as usize] as usize).sum();
+                        (table, cost)
+                    })
+                    .min_by_key(|&(_, cost)| cost)
+                    .unwrap();
+                *selector = best as u8;
             }
         }
 
-        let root = build_tree(&preorder[..], &inorder[..]);
+        let mut file_contents = vec![tables as u8];
+        push_varint(&mut file_contents, input.len() as u64);
 
-        let mut data_iter = data.into_iter();
-        let mut current_byte = data_iter.next().unwrap();
-        let mut num_bits = 0;
+        let mut bitmask = [0u8; 32];
+        (0..256)
+            .filter(|&b| used[b])
+            .for_each(|b| bitmask[b / 8] |= 1 << (b % 8));
+        file_contents.extend_from_slice(&bitmask);
 
-        let mut output_data = vec![];
-        while output_data.len() < file_len as usize {
-            let mut current_node = root.as_ref().unwrap();
-            while matches!(current_node.byte, Node::Internal(_)) {
-                if num_bits > 7 {
-                    current_byte = data_iter.next().unwrap();
-                    num_bits = 0;
-                }
+        for table in lengths.iter() {
+            file_contents.extend((0..256).filter(|&b| used[b]).map(|b| table[b]));
+        }
 
-                match 0x1 & (current_byte >> (7 - num_bits)) {
-                    0 => {
-                        current_node = current_node
-                            .left
-                            .as_ref()
-                            .expect("Expected to find a Huffman Node here.");
-                    }
-                    1 => {
-                        current_node = current_node
-                            .right
-                            .as_ref()
-                            .expect("Expected to find a Huffman Node here.");
-                    }
-                    _ => {
-                        panic!("Bit level error when &ing with 0x0");
-                    }
-                }
-                num_bits += 1;
+        log::info!("Encoding: File is {} bytes long", input.len());
+        log::info!(
+            "Encoding: {tables} tables, {} groups of {GROUP_SIZE}",
+            groups.len()
+        );
+
+        let paths: Vec<[Vec<u8>; 256]> = lengths.iter().map(canonical_paths).collect();
+        let mut writer = BitWriter::new(file_contents);
+        let mut order: Vec<u8> = (0..tables as u8).collect();
+        for &table in selectors.iter() {
+            let rank = order.iter().position(|&t| t == table).unwrap();
+            order.remove(rank);
+            order.insert(0, table);
+
+            let mut unary = vec![1; rank];
+            unary.push(0);
+            writer.push_path(&unary);
+        }
+        for (group, &table) in groups.iter().zip(selectors.iter()) {
+            for &byte in group.iter() {
+                writer.push_path(&paths[table as usize][byte as usize]);
             }
-            if let Node::Leaf(b) = current_node.byte {
-                output_data.push(b);
-            } else {
-                panic!("Huffman decoding ended on an internal node somehow.")
+        }
+        writer.finish()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn decode_multi(
+        input: Vec<u8>,
+        limits: &DecodeLimits,
+    ) -> Result<(Vec<u8>, Vec<Option<Box<HuffmanNode>>>, Vec<u8>), ParseError> {
+        let cut_off = || ParseError::from("Huffman header was cut off");
+        let (&tables, mut rest) = input.split_first().ok_or_else(cut_off)?;
+        let file_len = read_varint(&mut rest).ok_or_else(cut_off)?;
+        let file_len = usize::try_from(file_len).unwrap_or(usize::MAX);
+        limits.check("Huffman", file_len, 1)?;
+
+        let bitmask = rest.get(..32).ok_or_else(cut_off)?;
+        let mut rest = &rest[32..];
+        let used: Vec<usize> = (0..256)
+            .filter(|&b| bitmask[b / 8] & (1 << (b % 8)) != 0)
+            .collect();
+
+        let mut roots = vec![];
+        for _ in 0..tables {
+            let table = rest.get(..used.len()).ok_or_else(cut_off)?;
+            let mut lengths = [0u8; 256];
+            used.iter().zip(table).for_each(|(&b, &len)| lengths[b] = len);
+            if lengths.iter().any(|&len| len as usize > MAX_CODE_LENGTH) {
+                return Err(ParseError::from("Found a Huffman code longer than the limit"));
+            }
+            roots.push(tree_from_paths(&canonical_paths(&lengths)));
+            rest = &rest[used.len()..];
+        }
+
+        let mut reader = BitReader::new(rest);
+        let mut order: Vec<u8> = (0..tables).collect();
+        let mut selectors = vec![];
+        for _ in 0..file_len.div_ceil(GROUP_SIZE) {
+            let mut rank = 0;
+            while reader
+                .next_bit()
+                .ok_or(ParseError::from("Huffman selectors were cut off"))?
+                == 1
+            {
+                rank += 1;
+            }
+            let table = order.remove(rank.min(order.len() - 1));
+            order.insert(0, table);
+            selectors.push(table);
+        }
+
+        log::info!("Decoding: File is {file_len} bytes long");
+        log::info!(
+            "Decoding: Found {tables} tables, {} groups of {GROUP_SIZE}",
+            selectors.len()
+        );
+
+        let mut output_data = vec![];
+        for &table in selectors.iter() {
+            let root = &roots[table as usize];
+            for _ in 0..GROUP_SIZE.min(file_len - output_data.len()) {
+                output_data.push(decode_symbol(root, &mut reader)?);
             }
         }
-        output_data
+        Ok((output_data, roots, selectors))
     }
 }
@@ -1,4 +1,4 @@
-
+use super::encoder::ParseError;
 
 /// ENCODING HELPER FUNCTIONS
 
@@ -49,30 +49,230 @@ impl Ord for HuffmanNode {
     }
 }
 
-/// Recursive backtracking function to trace and return the path to a given byte
-/// in the Huffman tree
-pub fn encode_byte(node: &Option<Box<HuffmanNode>>, byte: u8, path: &mut Vec<u8>) -> bool {
-    if let Some(ref n) = node {
-        match n.byte {
-            Node::Leaf(b) => b == byte,
-            Node::Internal(_) => {
-                let left = encode_byte(&n.left, byte, path);
-                let right = encode_byte(&n.right, byte, path);
-                if left {
+/// Builds a Huffman tree from a frequency table, ignoring bytes with a frequency of 0.
+/// There must be at least one byte with a non-zero frequency.
+pub fn build_huffman_tree(freq_map: &[usize; 256]) -> Option<Box<HuffmanNode>> {
+    // Iterate through and construct HuffNodes for all non-zero frequency bytes
+    let mut freq_map: Vec<HuffmanNode> = freq_map
+        .iter()
+        .enumerate()
+        .filter_map(|(byte, &count)| match count > 0 {
+            true => Some(HuffmanNode {
+                byte: Node::Leaf(byte as u8),
+                frequency: count,
+                left: None,
+                right: None,
+            }),
+            false => None,
+        })
+        .collect();
+
+    // Huffman Tree creation:
+    // Pop smallest 2 frequencies
+    // Combine into 1 node
+    // Reinsert node
+    // Repeat until one node is left
+
+    let mut internal_count = 0;
+    while freq_map.len() > 1 {
+        freq_map.sort();
+        // Reverse so push and pop are zero-cost (least freq at end)
+        freq_map.reverse();
+
+        let n1 = freq_map.pop().expect("Expected to find a HuffNode");
+        let n2 = freq_map.pop().expect("Expected to find a HuffNode");
+
+        // When creating a parent node, convention is to place
+        // the smaller of the 2 children on the left
+        let combined_node = HuffmanNode {
+            byte: Node::Internal(internal_count),
+            frequency: n1.frequency + n2.frequency,
+            left: Some(Box::new(n1)),
+            right: Some(Box::new(n2)),
+        };
+
+        internal_count += 1;
+
+        // TODO: make this insertion to avoid resorting of array
+        freq_map.push(combined_node);
+    }
+
+    let root = freq_map
+        .pop()
+        .expect("Expected there to be more than 0 HuffNodes");
+    Some(Box::new(root))
+}
+
+/// Returns the path (0 = left, 1 = right) from the root to every byte in the tree.
+/// Bytes that aren't in the tree get an empty path.
+pub fn code_paths(root: &Option<Box<HuffmanNode>>) -> [Vec<u8>; 256] {
+    fn walk(node: &Option<Box<HuffmanNode>>, path: &mut Vec<u8>, paths: &mut [Vec<u8>; 256]) {
+        if let Some(ref n) = node {
+            match n.byte {
+                Node::Leaf(b) => paths[b as usize] = path.clone(),
+                Node::Internal(_) => {
                     path.push(0);
-                } else if right {
+                    walk(&n.left, path, paths);
+                    path.pop();
                     path.push(1);
+                    walk(&n.right, path, paths);
+                    path.pop();
                 }
-                return left || right;
             }
         }
-    } else {
-        false
     }
+
+    let mut paths = std::array::from_fn(|_| vec![]);
+    walk(root, &mut vec![], &mut paths);
+    paths
 }
 
-/// DECODING HELPER FUNCTIONS
+/// Returns the code length of every byte (0 = unused), never going over `max_length`.
+/// Like bzip2, lengths are limited by flattening the frequencies and rebuilding until the tree is shallow enough.
+/// A byte that is alone in the tree still gets a length of 1, so it has a code.
+pub fn code_lengths(freq_map: &[usize; 256], max_length: usize) -> [u8; 256] {
+    let mut freqs = *freq_map;
+    loop {
+        let paths = code_paths(&build_huffman_tree(&freqs));
+        if paths.iter().all(|p| p.len() <= max_length) {
+            let mut lengths = [0; 256];
+            for byte in 0..256 {
+                if freqs[byte] > 0 {
+                    lengths[byte] = paths[byte].len().max(1) as u8;
+                }
+            }
+            return lengths;
+        }
+        freqs
+            .iter_mut()
+            .filter(|f| **f > 0)
+            .for_each(|f| *f = *f / 2 + 1);
+    }
+}
+
+/// Assigns canonical codes from code lengths: shorter codes first, ties broken by byte value
+pub fn canonical_paths(lengths: &[u8; 256]) -> [Vec<u8>; 256] {
+    let mut bytes: Vec<usize> = (0..256).filter(|&b| lengths[b] > 0).collect();
+    bytes.sort_by_key(|&b| (lengths[b], b));
+
+    let mut paths: [Vec<u8>; 256] = std::array::from_fn(|_| vec![]);
+    let mut code: u64 = 0;
+    let mut previous_length = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let length = lengths[byte];
+        if i > 0 {
+            code = (code + 1) << (length - previous_length);
+        }
+        paths[byte] = (0..length).rev().map(|bit| ((code >> bit) & 1) as u8).collect();
+        previous_length = length;
+    }
+    paths
+}
+
+/// Rebuilds a tree from the code paths of every byte, for decoding
+pub fn tree_from_paths(paths: &[Vec<u8>; 256]) -> Option<Box<HuffmanNode>> {
+    let new_node = |byte| {
+        Box::new(HuffmanNode {
+            byte,
+            frequency: 0, // Frequency is not used when decoding, so we set it to 0.
+            left: None,
+            right: None,
+        })
+    };
 
+    // Internal nodes are numbered in the order they're created, which only matters for telling them apart
+    let mut internal_count: u8 = 0;
+    let mut root = new_node(Node::Internal(0));
+    for (byte, path) in paths.iter().enumerate().filter(|(_, p)| !p.is_empty()) {
+        let mut node = &mut root;
+        for (depth, &turn) in path.iter().enumerate() {
+            let child = match turn {
+                0 => &mut node.left,
+                _ => &mut node.right,
+            };
+            let is_last = depth == path.len() - 1;
+            node = child.get_or_insert_with(|| match is_last {
+                true => new_node(Node::Leaf(byte as u8)),
+                false => {
+                    internal_count = internal_count.wrapping_add(1);
+                    new_node(Node::Internal(internal_count))
+                }
+            });
+        }
+    }
+    Some(root)
+}
+
+/// Sets the frequency of every Leaf to its byte's count, and of every Internal node to the sum of its children.
+/// Returns the frequency of `node`.
+pub fn fill_frequencies(node: &mut Option<Box<HuffmanNode>>, counts: &[usize; 256]) -> usize {
+    let Some(n) = node else {
+        return 0;
+    };
+    n.frequency = match n.byte {
+        Node::Leaf(b) => counts[b as usize],
+        Node::Internal(_) => fill_frequencies(&mut n.left, counts) + fill_frequencies(&mut n.right, counts),
+    };
+    n.frequency
+}
+
+/// Serializes a traversal as `(0, id)` for Internal nodes and `(1, byte)` for Leaf nodes
+pub fn serialize_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * 2);
+    for node in nodes {
+        match node {
+            Node::Leaf(b) => {
+                bytes.push(1);
+                bytes.push(*b);
+            }
+            Node::Internal(b) => {
+                bytes.push(0);
+                bytes.push(*b);
+            }
+        }
+    }
+    bytes
+}
+
+/// Packs bits into bytes, most significant bit first. The last byte is padded with 0's.
+pub struct BitWriter {
+    output: Vec<u8>,
+    current_byte: u8,
+    num_bits: u8,
+}
+
+impl BitWriter {
+    /// Creates a writer which appends to `output`
+    pub fn new(output: Vec<u8>) -> Self {
+        Self {
+            output,
+            current_byte: 0,
+            num_bits: 0,
+        }
+    }
+
+    pub fn push_path(&mut self, path: &[u8]) {
+        for turn in path {
+            if self.num_bits > 7 {
+                self.output.push(self.current_byte);
+                self.current_byte = 0;
+                self.num_bits = 0;
+            }
+
+            self.current_byte |= turn << (7 - self.num_bits);
+            self.num_bits += 1;
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.num_bits > 0 {
+            self.output.push(self.current_byte);
+        }
+        self.output
+    }
+}
+
+/// DECODING HELPER FUNCTIONS
 /// Constructs a Preorder array of nodes, where `(Some(u8), None) = (Leaf(u8), Internal)`
 pub fn pre_order(node: &Option<Box<HuffmanNode>>, arr: &mut Vec<Node>) {
     if let Some(ref n) = node {
@@ -82,6 +282,25 @@ pub fn pre_order(node: &Option<Box<HuffmanNode>>, arr: &mut Vec<Node>) {
     }
 }
 
+/// Reads back the output of `serialize_nodes`
+pub fn deserialize_nodes(bytes: &[u8]) -> Result<Vec<Node>, ParseError> {
+    let mut iter = bytes.iter();
+    let mut nodes = vec![];
+    while let Some(&byte) = iter.next() {
+        let value = *iter.next().ok_or(ParseError::from("Huffman tree was cut off"))?;
+        match byte {
+            0 => {
+                nodes.push(Node::Internal(value));
+            }
+            1 => {
+                nodes.push(Node::Leaf(value));
+            }
+            _ => return Err(ParseError::from("Found an unexpected byte")),
+        }
+    }
+    Ok(nodes)
+}
+
 /// Constructs an Inorder array of nodes, where `(Some(u8), None) = (Leaf(u8), Internal)`
 pub fn in_order(node: &Option<Box<HuffmanNode>>, arr: &mut Vec<Node>) {
     if let Some(ref n) = node {
@@ -92,9 +311,9 @@ pub fn in_order(node: &Option<Box<HuffmanNode>>, arr: &mut Vec<Node>) {
 }
 
 
-pub fn build_tree(preorder: &[Node], inorder: &[Node]) -> Option<Box<HuffmanNode>> {
-    if preorder.len() == 0 || inorder.len() == 0 {
-        return None;
+pub fn build_tree(preorder: &[Node], inorder: &[Node]) -> Result<Option<Box<HuffmanNode>>, ParseError> {
+    if preorder.is_empty() || inorder.is_empty() {
+        return Ok(None);
     }
     let mut root = Some(Box::new(HuffmanNode {
         byte: preorder[0],
@@ -103,9 +322,66 @@ pub fn build_tree(preorder: &[Node], inorder: &[Node]) -> Option<Box<HuffmanNode
         right: None,
     }));
 
-    let mid = inorder.iter().position(|r| *r == preorder[0]).unwrap();
-    root.as_mut().unwrap().left = build_tree(&preorder[1..mid + 1], &inorder[0..mid]);
-    root.as_mut().unwrap().right = build_tree(&preorder[mid + 1..], &inorder[mid + 1..]);
+    let mid = inorder
+        .iter()
+        .position(|r| *r == preorder[0])
+        .filter(|&mid| mid < preorder.len())
+        .ok_or(ParseError::from("Huffman tree traversals don't match"))?;
+    root.as_mut().unwrap().left = build_tree(&preorder[1..mid + 1], &inorder[0..mid])?;
+    root.as_mut().unwrap().right = build_tree(&preorder[mid + 1..], &inorder[mid + 1..])?;
+
+    Ok(root)
+}
+
+/// Reads bits back out of bytes, most significant bit first
+pub struct BitReader<'a> {
+    data: std::slice::Iter<'a, u8>,
+    current_byte: u8,
+    num_bits: u8,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data: data.iter(),
+            current_byte: 0,
+            // Start "past the end" of a byte, so the first bit read pulls in a fresh one
+            num_bits: 8,
+        }
+    }
+
+    pub fn next_bit(&mut self) -> Option<u8> {
+        if self.num_bits > 7 {
+            self.current_byte = *self.data.next()?;
+            self.num_bits = 0;
+        }
+        let bit = 0x1 & (self.current_byte >> (7 - self.num_bits));
+        self.num_bits += 1;
+        Some(bit)
+    }
+}
 
-    return root;
+/// Walks the tree from the root, one bit at a time, until a Leaf is reached.
+/// A tree that is just a Leaf decodes without reading any bits.
+pub fn decode_symbol(root: &Option<Box<HuffmanNode>>, reader: &mut BitReader) -> Result<u8, ParseError> {
+    let mut current_node = root
+        .as_ref()
+        .ok_or(ParseError::from("Expected to find a Huffman Tree"))?;
+    loop {
+        match current_node.byte {
+            Node::Leaf(b) => return Ok(b),
+            Node::Internal(_) => {
+                let next = match reader
+                    .next_bit()
+                    .ok_or(ParseError::from("Huffman data was cut off"))?
+                {
+                    0 => &current_node.left,
+                    _ => &current_node.right,
+                };
+                current_node = next
+                    .as_ref()
+                    .ok_or(ParseError::from("Expected to find a Huffman Node here."))?;
+            }
+        }
+    }
 }
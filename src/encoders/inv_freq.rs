@@ -0,0 +1,195 @@
+use crate::*;
+
+use super::{encoder::ParseError, limits::DecodeLimits};
+
+/*
+    Inversion Frequencies (Arnavut & Magliveras) are an alternative to MTF as the second step after the BWT.
+
+    Instead of ranking every byte against recently used ones, each distinct byte is handled on its own, smallest first.
+    For every occurrence of a byte, we write how many *larger* bytes sit between it and its previous occurrence
+    (or the start of the data). The largest byte doesn't need any values at all, since it simply fills whatever is left.
+
+    banana -> a: 1 1 1    b: 0    n: (implied)
+
+    BWT output clusters identical bytes together, so most of these gaps end up small, which is what the entropy coder wants.
+
+    Stream layout: [distinct bytes - 1] ([byte] [varint count])... [varint gaps]...
+*/
+
+pub struct If;
+
+impl If {
+    pub fn encode(input: Vec<u8>) -> Vec<u8> {
+        if input.is_empty() {
+            return input;
+        }
+
+        // Bucket the positions of every byte, in order
+        let mut positions: Vec<Vec<usize>> = vec![vec![]; 256];
+        input
+            .iter()
+            .enumerate()
+            .for_each(|(index, &b)| positions[b as usize].push(index));
+
+        let symbols: Vec<u8> = (0..=255u8)
+            .filter(|&b| !positions[b as usize].is_empty())
+            .collect();
+
+        let mut output: Vec<u8> = vec![(symbols.len() - 1) as u8];
+        for &s in symbols.iter() {
+            output.push(s);
+            push_varint(&mut output, positions[s as usize].len() as u64);
+        }
+
+        log::info!("Encoding: Found {} distinct bytes", symbols.len());
+
+        // Every position starts out free, and is taken as soon as its byte has been handled.
+        // A free position before `p` therefore always holds a larger byte than the current one.
+        let mut free = FenwickTree::filled(input.len());
+        for &s in &symbols[..symbols.len() - 1] {
+            let mut previous_rank = 0usize;
+            for &position in positions[s as usize].iter() {
+                let rank = free.prefix_sum(position);
+                push_varint(&mut output, (rank - previous_rank) as u64);
+                free.remove(position);
+                previous_rank = rank;
+            }
+        }
+
+        output
+    }
+
+    /// Reads the header fields of an encoded stream, for --list
+    pub fn describe(input: &[u8]) -> Option<Vec<(&'static str, String)>> {
+        if input.is_empty() {
+            return Some(vec![]);
+        }
+        let (&symbol_count, mut data) = input.split_first()?;
+        let mut len = 0;
+        for _ in 0..=symbol_count {
+            data = data.get(1..)?;
+            len = read_varint(&mut data)?.saturating_add(len);
+        }
+        Some(vec![
+            ("distinct bytes", (symbol_count as usize + 1).to_string()),
+            ("decoded length", len.to_string()),
+        ])
+    }
+
+    pub fn decode(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        if input.is_empty() {
+            return Ok(input);
+        }
+
+        let mut data = &input[..];
+        let (&symbol_count, rest) = data.split_first().unwrap();
+        data = rest;
+
+        let mut symbols: Vec<(u8, usize)> = vec![];
+        for _ in 0..=symbol_count {
+            let (&s, rest) = data.split_first().ok_or(ParseError::from("IF header was cut off"))?;
+            data = rest;
+            let count = read_varint(&mut data).ok_or(ParseError::from("IF header was cut off"))?;
+            symbols.push((s, count as usize));
+        }
+
+        let len = symbols
+            .iter()
+            .try_fold(0usize, |len, (_, count)| len.checked_add(*count))
+            .ok_or(ParseError::from("IF header has an impossible length"))?;
+        log::info!("Decoding: Found {} distinct bytes, {len} bytes total", symbols.len());
+        // Every position is a free slot in the Fenwick tree until it is filled, then a byte of the output
+        let bytes_per_symbol = size_of::<Option<u8>>() + size_of::<usize>() + 1;
+        limits.check("IF", len, bytes_per_symbol)?;
+
+        let (&(last_symbol, _), symbols) = symbols.split_last().unwrap();
+        // Every position but the last symbol's takes at least a byte of gaps, so those counts can't claim more
+        // than is left. The last symbol fills in the rest, which only the limits can bound.
+        let explicit = symbols.iter().map(|&(_, count)| count).sum::<usize>();
+        if explicit > data.len() {
+            return Err(ParseError::from("IF header counts more positions than the data holds"));
+        }
+
+        let mut output: Vec<Option<u8>> = vec![None; len];
+        let mut free = FenwickTree::filled(len);
+        for &(s, count) in symbols {
+            let mut previous_rank = 0usize;
+            for _ in 0..count {
+                let gap = read_varint(&mut data).ok_or(ParseError::from("IF data was cut off"))? as usize;
+                let rank = previous_rank
+                    .checked_add(gap)
+                    .ok_or(ParseError::from("IF gap points past the end of the data"))?;
+                let position = free
+                    .find(rank)
+                    .ok_or(ParseError::from("IF gap points past the end of the data"))?;
+                output[position] = Some(s);
+                free.remove(position);
+                previous_rank = rank;
+            }
+        }
+
+        // The largest byte takes every position that is left over
+        Ok(output
+            .into_iter()
+            .map(|b| b.unwrap_or(last_symbol))
+            .collect())
+    }
+}
+
+/// A Fenwick (Binary Indexed) tree counting free positions, used to find ranks among free positions in O(log n)
+struct FenwickTree {
+    tree: Vec<usize>,
+}
+
+impl FenwickTree {
+    /// Creates a tree of `len` positions, all of them free
+    fn filled(len: usize) -> Self {
+        let mut tree = vec![0; len + 1];
+        for i in 1..=len {
+            tree[i] += 1;
+            let parent = i + (i & i.wrapping_neg());
+            if parent <= len {
+                tree[parent] += tree[i];
+            }
+        }
+        Self { tree }
+    }
+
+    /// Number of free positions before `position`
+    fn prefix_sum(&self, position: usize) -> usize {
+        let mut sum = 0;
+        let mut i = position;
+        while i > 0 {
+            sum += self.tree[i];
+            i -= i & i.wrapping_neg();
+        }
+        sum
+    }
+
+    /// Marks `position` as taken. It must currently be free.
+    fn remove(&mut self, position: usize) {
+        let mut i = position + 1;
+        while i < self.tree.len() {
+            self.tree[i] -= 1;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// Finds the free position with `rank` free positions before it
+    fn find(&self, rank: usize) -> Option<usize> {
+        let len = self.tree.len() - 1;
+        let mut position = 0;
+        let mut remaining = rank;
+        let mut step = len.checked_next_power_of_two()?;
+        while step > 0 {
+            let next = position + step;
+            if next <= len && self.tree[next] <= remaining {
+                position = next;
+                remaining -= self.tree[next];
+            }
+            step >>= 1;
+        }
+        // `position` is now the number of positions whose prefix holds at most `rank` free ones
+        (position < len).then_some(position)
+    }
+}
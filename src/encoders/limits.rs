@@ -0,0 +1,71 @@
+use super::encoder::ParseError;
+
+/*
+    Limits on how much decoding may allocate, for input that can't be trusted.
+
+    Every length a decoder works from comes out of the data itself: the Huffman header's file length,
+    IF's symbol counts, BWT's block sizes, RLE's run counts. A few bytes can claim gigabytes of output, and a decoder
+    that believes them allocates it all before finding out the data was never there. Worse, a Huffman tree that is
+    a single Leaf codes every symbol in 0 bits, so it really does produce however many bytes the header asks for.
+
+    So every stage checks its limits before allocating anything that depends on those lengths:
+    - max_output_size: the most bytes any single stage may decode to (including the final output).
+      Checked against the length a header claims up front, or as the output grows for stages that can't know
+      it in advance (RLE runs).
+    - max_memory: the most bytes any single stage may allocate while decoding, output included. Stages with
+      tables on top of their output (BWT's sort and map, IF's free positions) count those too, per byte decoded.
+
+    Here the default is no limit at all, since a library can't guess what its caller can afford, and a file it
+    compressed itself can be trusted. Anything decompressing uploads (or anything else from the outside) should
+    set both on `Tokens::limits`. The CLI does the same for local files, which are often far larger than any
+    sensible limit (a single-block BWT of a 40 MB file already needs 4 GB), but holds stdin to `UNTRUSTED`
+    unless --max-output-size and --max-memory say otherwise, since it may well come straight off the network.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// The most bytes a single stage may decode to
+    pub max_output_size: usize,
+    /// The most bytes a single stage may allocate while decoding, its output included
+    pub max_memory: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+impl DecodeLimits {
+    pub const UNLIMITED: Self = Self {
+        max_output_size: usize::MAX,
+        max_memory: usize::MAX,
+    };
+
+    /// What the CLI decodes stdin with by default
+    pub const UNTRUSTED: Self = Self {
+        max_output_size: 2_000_000_000,
+        max_memory: 4_000_000_000,
+    };
+
+    /// Checks that `stage` may decode to `len` bytes, when it takes `bytes_per_symbol` bytes of memory
+    /// for every one of them
+    pub fn check(&self, stage: &str, len: usize, bytes_per_symbol: usize) -> Result<(), ParseError> {
+        if len > self.max_output_size {
+            return Err(format!(
+                "{stage} would decode to {len} bytes, over the limit of {} (see --max-output-size)",
+                self.max_output_size
+            )
+            .into());
+        }
+        let memory = len.saturating_mul(bytes_per_symbol);
+        if memory > self.max_memory {
+            return Err(format!(
+                "{stage} would need {memory} bytes of memory to decode, over the limit of {} (see --max-memory)",
+                self.max_memory
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,13 @@
 pub mod bwt;
+pub mod delta;
 pub mod rle;
+pub mod rle1;
 pub mod mtf;
 pub mod huff;
 pub mod huff_helper;
+pub mod inv_freq;
+pub mod limits;
+
+pub mod auto;
 
 pub mod encoder;
\ No newline at end of file
@@ -1,4 +1,6 @@
-use crate::index_of;
+use std::fmt::Display;
+
+use super::{encoder::ParseError, limits::DecodeLimits};
 
 /*
     This MTF Encoder is based off of an Adaptive-MTF algorithm by Brandon Simmons.
@@ -33,6 +35,142 @@ use crate::index_of;
 
 pub struct Mtf;
 
+/// Marks a stream written in the current format. Legacy streams start with their alphabet followed by its first byte again,
+/// so they could only start with 2 identical bytes for a single-symbol alphabet, which the legacy decoder couldn't handle anyway.
+const FORMAT_MARKER: [u8; 2] = [0xFF, 0xFF];
+
+/// Defines the rule used to update the ranks after each byte. All variants share the same stream layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum MtfVariant {
+    /// Classic Move-To-Front: the byte always moves to rank 0
+    #[default]
+    Mtf = 0,
+    /// A byte at rank 1 moves to the front, any other byte moves to rank 1
+    Mtf1 = 1,
+    /// Like MTF-1, but a byte at rank 1 only moves to the front if the previous rank wasn't 0
+    Mtf2 = 2,
+    /// Weighted Frequency Count: bytes are ranked by an exponentially decaying count of their occurrences
+    Wfc = 3,
+}
+
+impl TryFrom<u8> for MtfVariant {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Mtf),
+            1 => Ok(Self::Mtf1),
+            2 => Ok(Self::Mtf2),
+            3 => Ok(Self::Wfc),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for MtfVariant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mtf => write!(f, "mtf"),
+            Self::Mtf1 => write!(f, "mtf1"),
+            Self::Mtf2 => write!(f, "mtf2"),
+            Self::Wfc => write!(f, "wfc"),
+        }
+    }
+}
+
+// WFC weights: every byte adds the current increment to its weight, and the increment grows by 1/4 each step.
+// This is the same as decaying every other weight by a constant factor, without having to touch them all.
+const WFC_INITIAL_INCREMENT: u64 = 1 << 16;
+const WFC_RESCALE_LIMIT: u64 = 1 << 56;
+const WFC_RESCALE_SHIFT: u32 = 40;
+
+/// The rank state shared by the encoder & decoder.
+/// `order[rank]` is the byte at that rank, and `ranks[byte]` is the rank of that byte.
+struct RankTable {
+    variant: MtfVariant,
+    order: [u8; 256],
+    ranks: [u8; 256],
+    weights: [u64; 256],
+    increment: u64,
+    last_rank: u8,
+}
+
+impl RankTable {
+    /// Builds the initial ranks, with `alphabet` at the front in order, followed by all unused bytes
+    fn new(variant: MtfVariant, alphabet: &[u8]) -> Self {
+        let mut order = [0; 256];
+        let mut seen = [false; 256];
+        let mut rank = 0;
+        for b in alphabet.iter().copied().chain(0..=255u8) {
+            if !seen[b as usize] {
+                seen[b as usize] = true;
+                order[rank] = b;
+                rank += 1;
+            }
+        }
+
+        let mut ranks = [0; 256];
+        order.iter().enumerate().for_each(|(rank, &b)| ranks[b as usize] = rank as u8);
+
+        Self {
+            variant,
+            order,
+            ranks,
+            weights: [0; 256],
+            increment: WFC_INITIAL_INCREMENT,
+            // Start as if the last rank was 0, so MTF-2 begins in the cautious state
+            last_rank: 0,
+        }
+    }
+
+    /// Moves the byte at `rank` to `target` (with `target <= rank`), shifting everything in between back by one
+    fn promote(&mut self, rank: usize, target: usize) {
+        let byte = self.order[rank];
+        self.order.copy_within(target..rank, target + 1);
+        self.order[target] = byte;
+        for r in target..=rank {
+            self.ranks[self.order[r] as usize] = r as u8;
+        }
+    }
+
+    /// Updates the ranks after `rank` was used
+    fn update(&mut self, rank: u8) {
+        let r = rank as usize;
+        match self.variant {
+            MtfVariant::Mtf => self.promote(r, 0),
+            MtfVariant::Mtf1 => self.promote(r, if r == 1 { 0 } else { r.min(1) }),
+            MtfVariant::Mtf2 => {
+                let target = match r {
+                    0 => 0,
+                    1 if self.last_rank != 0 => 0,
+                    _ => 1,
+                };
+                self.promote(r, target);
+            }
+            MtfVariant::Wfc => {
+                let byte = self.order[r] as usize;
+                self.weights[byte] += self.increment;
+                self.increment += self.increment >> 2;
+
+                if self.increment > WFC_RESCALE_LIMIT {
+                    self.weights.iter_mut().for_each(|w| *w >>= WFC_RESCALE_SHIFT);
+                    self.increment >>= WFC_RESCALE_SHIFT;
+                }
+
+                // Only this byte's weight went up, so it just has to bubble up past lighter bytes
+                let weight = self.weights[byte];
+                let mut target = r;
+                while target > 0 && self.weights[self.order[target - 1] as usize] < weight {
+                    target -= 1;
+                }
+                self.promote(r, target);
+            }
+        }
+        self.last_rank = rank;
+    }
+}
+
 /*
     This MTF Encoder is based off of an Adaptive-MTF algorithm by Brandon Simmons.
     http://brandon.si/code/an-adaptive-move-to-front-algorithm/
@@ -42,52 +180,116 @@ pub struct Mtf;
     This comes at the cost of including the alphabet in the file itself so it can be decoded.
     The true "cost" of this "key" is only the number of unique characters in the orginal string,
     meaning it is upper-bounded by 256 usually.
+
+    The alphabet is stored in order of first appearance, which means every byte's first occurrence
+    is ranked right behind all of the bytes seen before it, just like the original adaptive approach.
+
+    Stream layout: [0xFF 0xFF] [variant] [alphabet length - 1] [alphabet] [ranks]
 */
 impl Mtf {
-    pub fn encode(input: Vec<u8>) -> Vec<u8> {
-        if input.len() == 0 {
+    pub fn encode(input: Vec<u8>, variant: MtfVariant) -> Vec<u8> {
+        if input.is_empty() {
             return input;
         }
 
-        // Start with empty alphabet and append to it as we find more
+        // Collect the alphabet in order of first appearance
+        let mut seen = [false; 256];
         let mut alphabet: Vec<u8> = vec![];
-        let mut data: Vec<u8> = vec![];
-        input.iter().for_each(|&byte| match index_of(&alphabet, &byte) {
-            Some(index) => {
-                alphabet.remove(index);
-                alphabet.insert(0, byte);
-                // Since we're dealing with bytes, we know the index in the alphabet
-                // is limited by 255 and can be held in a u8
-                data.push(index as u8);
-            }
-            None => {
-                alphabet.insert(0, byte);
-                data.push((alphabet.len() - 1) as u8);
+        input.iter().for_each(|&b| {
+            if !seen[b as usize] {
+                seen[b as usize] = true;
+                alphabet.push(b);
             }
         });
-        let mut print_str = String::new();
-        alphabet.iter().for_each(|&b| {
-            let c = match b {
-                32..=126 => char::from(b),
-                _ => '.',
-            };
-            print_str.push(c);
-        });
-        log::info!("Using alphabet [{} distinct bytes] (ASCII representation):", alphabet.len());
-        log::info!("{print_str}");
-        // Indicate the end of the alphabet by appending the first byte again
-        alphabet.push(*alphabet.first().expect("There should have been an alphabet lol..."));
-
-        // Append the header & data together
-        alphabet.append(&mut data);
-        alphabet
+
+        log::info!("Using variant {variant}");
+        log_alphabet("Using", &alphabet);
+
+        let mut output: Vec<u8> = Vec::with_capacity(input.len() + alphabet.len() + 4);
+        output.extend_from_slice(&FORMAT_MARKER);
+        output.push(variant as u8);
+        output.push((alphabet.len() - 1) as u8);
+        output.extend_from_slice(&alphabet);
+
+        let mut table = RankTable::new(variant, &alphabet);
+        for &byte in input.iter() {
+            let rank = table.ranks[byte as usize];
+            output.push(rank);
+            table.update(rank);
+        }
+        output
+    }
+
+    pub fn decode(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        if !input.starts_with(&FORMAT_MARKER) {
+            return Self::decode_legacy(input, limits);
+        }
+
+        let (&variant, rest) = input[FORMAT_MARKER.len()..]
+            .split_first()
+            .ok_or(ParseError::from("MTF header was cut off"))?;
+        let variant = MtfVariant::try_from(variant).map_err(|_| ParseError::from("Found an unknown MTF variant"))?;
+
+        let (&alphabet_len, rest) = rest.split_first().ok_or(ParseError::from("MTF header was cut off"))?;
+        if rest.len() <= alphabet_len as usize {
+            return Err(ParseError::from("MTF alphabet was cut off"));
+        }
+        let (alphabet, ranks) = rest.split_at(alphabet_len as usize + 1);
+
+        log::info!("Found variant {variant}");
+        log_alphabet("Found", alphabet);
+
+        limits.check("MTF", ranks.len(), 1)?;
+        let mut table = RankTable::new(variant, alphabet);
+        let mut output: Vec<u8> = Vec::with_capacity(ranks.len());
+        for &rank in ranks {
+            output.push(table.order[rank as usize]);
+            table.update(rank);
+        }
+        Ok(output)
+    }
+
+    /// Reads the header fields of an encoded stream, for --list
+    pub fn describe(input: &[u8]) -> Option<Vec<(&'static str, String)>> {
+        if input.is_empty() {
+            return Some(vec![]);
+        }
+        if let Some(rest) = input.strip_prefix(&FORMAT_MARKER) {
+            let variant = MtfVariant::try_from(*rest.first()?).ok()?;
+            let alphabet_len = *rest.get(1)? as usize + 1;
+            return Some(vec![
+                ("variant", variant.to_string()),
+                ("alphabet size", alphabet_len.to_string()),
+            ]);
+        }
+
+        // The legacy alphabet ends at the second occurance of its first byte
+        let alphabet_len = input.iter().skip(2).position(|&b| b == input[0])? + 2;
+        Some(vec![
+            ("format", "legacy".to_string()),
+            ("alphabet size", alphabet_len.to_string()),
+        ])
+    }
+
+    /// Reads the alphabet stored in an encoded stream, for `inspect`
+    pub fn alphabet(input: &[u8]) -> Option<&[u8]> {
+        if let Some(rest) = input.strip_prefix(&FORMAT_MARKER) {
+            let alphabet_len = *rest.get(1)? as usize + 1;
+            return rest.get(2..2 + alphabet_len);
+        }
+        // The legacy alphabet ends at the second occurance of its first byte
+        let &first = input.first()?;
+        let alphabet_len = input.iter().skip(2).position(|&b| b == first)? + 2;
+        input.get(..alphabet_len)
     }
 
-    pub fn decode(input: Vec<u8>) -> Vec<u8> {
+    /// Decodes the original format, where the final alphabet was stored (terminated by a repeat of its first byte),
+    /// and the ranks were replayed backwards.
+    fn decode_legacy(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
         let mut alphabet: Vec<u8> = vec![];
         let mut output: Vec<u8> = vec![];
         let mut indices: &[u8] = &[];
-        
+
         // Split the input at the second occurance of the first byte
         for (index, &byte) in input.iter().enumerate() {
             if alphabet.len() > 1 && *alphabet.first().unwrap() == byte {
@@ -96,25 +298,37 @@ impl Mtf {
             }
             alphabet.push(byte);
         }
-        let mut print_str = String::new();
-        alphabet.iter().for_each(|&b| {
-            let c = match b {
-                32..=126 => char::from(b),
-                _ => '.',
-            };
-            print_str.push(c);
-        });
-        log::info!("Found alphabet [{} distinct bytes] (ASCII representation):", alphabet.len());
-        log::info!("{print_str}");
+        log_alphabet("Found legacy", &alphabet);
         let indices: Vec<u8> = indices.into();
+        limits.check("MTF", indices.len(), 1)?;
 
+        if alphabet.is_empty() && !indices.is_empty() {
+            return Err(ParseError::from("MTF alphabet is missing"));
+        }
         for &index in indices.iter().rev() {
+            if index as usize >= alphabet.len() {
+                return Err(ParseError::from("MTF index points past the end of the alphabet"));
+            }
             let head = alphabet.remove(0);
             alphabet.insert(index as usize, head);
             output.push(head);
         }
-        output.into_iter().rev().collect()
+        Ok(output.into_iter().rev().collect())
     }
-
 }
 
+/// Logs the alphabet's ASCII representation, with `.` standing in for unprintable bytes
+fn log_alphabet(prefix: &str, alphabet: &[u8]) {
+    let print_str: String = alphabet
+        .iter()
+        .map(|&b| match b {
+            32..=126 => char::from(b),
+            _ => '.',
+        })
+        .collect();
+    log::info!(
+        "{prefix} alphabet [{} distinct bytes] (ASCII representation):",
+        alphabet.len()
+    );
+    log::info!("{print_str}");
+}
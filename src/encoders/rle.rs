@@ -2,89 +2,132 @@ use std::ops::Div;
 
 use crate::*;
 
-// Upper & lower bounds for number of consecutive characters that induce an RLE replacement
-// Lower bound = 4 because aaaa -> (DELIM)4a    4 bytes -> 3 bytes. Saves at least 1 byte
-const MIN_REPEAT_COUNT: u8 = 4;
+use super::{encoder::ParseError, limits::DecodeLimits};
+
+/*
+    Runs are written escape-free: once a byte has been seen MIN_REPEAT_COUNT times in a row,
+    the very next thing in the stream is a varint holding how many *more* copies follow (possibly 0).
+
+    aaaaaaaa -> aaa(5)      abbbc -> abbb(0)c      ab -> ab
+
+    Since the decoder counts repeats itself, there is nothing to escape and no delimiter to pick,
+    and varint counts mean runs are no longer capped at 255.
+
+    Streams produced by this format start with a backslash. The old format always started with its delimiter,
+    which could never be a backslash (it was reserved for escaping), so old .pkz files still decode.
+*/
+
+// Number of consecutive identical bytes written out before a run count follows.
+// 3 is the sweet spot: aaa -> aaa(0) costs a byte, aaaa -> aaa(1) breaks even, and longer runs save space
+const MIN_REPEAT_COUNT: usize = 3;
+
+// First byte of every stream in the current format
+const FORMAT_MARKER: u8 = b'\\';
 
 pub struct Rle;
 
 impl Rle {
-    pub fn encode(mut input: Vec<u8>) -> Vec<u8> {
-        if input.len() == 0 {
+    pub fn encode(input: Vec<u8>) -> Vec<u8> {
+        if input.is_empty() {
             return input;
         }
 
-        let mut output: Vec<u8> = vec![];
-        let delim = get_least_used_byte(&input);
-        log::info!("Using {delim} ({}) as delim", char::from(delim));
-
-        insert_before_target(&mut input, b'\\', b'\\');
-        insert_before_target(&mut input, delim, b'\\');
-
-        let mut count = 1;
-        let mut last_byte = input[0];
-
-        let input_iter = input[1..].iter();
-        for &current_byte in input_iter {
-            // If we encounter a new byte OR we hit the repeat limit
-            // potentially append run length encoded vec to the output
-            if current_byte != last_byte || count == u8::MAX {
-                if count < MIN_REPEAT_COUNT {
-                    // If we're under the minimum repeat count, append the raw bytes as is
-                    (0..count).into_iter().for_each(|_| output.push(last_byte));
-                } else {
-                    // Otherwise, it means MIN <= count <= MAX, so we append the encoding
-                    output.push(delim);
-                    output.push(count);
-                    output.push(last_byte);
-                }
-                count = 1;
+        let mut output: Vec<u8> = Vec::with_capacity(input.len() + 1);
+        output.push(FORMAT_MARKER);
+
+        let mut index = 0;
+        while index < input.len() {
+            let byte = input[index];
+            let run = input[index..].iter().take_while(|&&b| b == byte).count();
+
+            if run < MIN_REPEAT_COUNT {
+                output.extend(std::iter::repeat_n(byte, run));
             } else {
-                count += 1;
+                output.extend(std::iter::repeat_n(byte, MIN_REPEAT_COUNT));
+                push_varint(&mut output, (run - MIN_REPEAT_COUNT) as u64);
             }
-            last_byte = current_byte;
-        }
-        // Deal with the last byte
-        if MIN_REPEAT_COUNT <= count {
-            output.push(delim);
-            output.push(count);
-            output.push(last_byte);
-        } else {
-            (0..count).into_iter().for_each(|_| output.push(last_byte));
+            index += run;
         }
 
-        output.insert(0, delim);
         output
     }
 
-    pub fn decode(input: Vec<u8>) -> Vec<u8> {
+    pub fn decode(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        match input.first() {
+            None => Ok(input),
+            Some(&FORMAT_MARKER) => {
+                let mut output: Vec<u8> = vec![];
+                let mut bytes = &input[1..];
+
+                let mut last_byte = None;
+                let mut count = 0;
+                while let Some((&b, rest)) = bytes.split_first() {
+                    bytes = rest;
+                    output.push(b);
+
+                    count = if last_byte == Some(b) { count + 1 } else { 1 };
+                    last_byte = Some(b);
+
+                    if count == MIN_REPEAT_COUNT {
+                        let extra = read_varint(&mut bytes).ok_or(ParseError::from("RLE run count was cut off"))?;
+                        let extra = usize::try_from(extra).unwrap_or(usize::MAX);
+                        limits.check("RLE", output.len().saturating_add(extra), 1)?;
+                        output.extend(std::iter::repeat_n(b, extra));
+                        // A run always ends at its count, so the next byte starts counting from scratch
+                        last_byte = None;
+                    }
+                }
+
+                Ok(output)
+            }
+            Some(_) => Self::decode_legacy(input, limits),
+        }
+    }
+
+    /// Reads the header fields of an encoded stream, for --list
+    pub fn describe(input: &[u8]) -> Option<Vec<(&'static str, String)>> {
+        let fields = match input.first() {
+            None => vec![],
+            Some(&FORMAT_MARKER) => vec![("format", "escape-free".to_string())],
+            Some(&delim) => vec![
+                ("format", "legacy".to_string()),
+                ("delimiter", format!("{delim:#04x}")),
+            ],
+        };
+        Some(fields)
+    }
+
+    /// Decodes the old `[delim][data]` format, where runs were written as `delim count byte`
+    /// and both the delim and backslash were escaped with a backslash.
+    fn decode_legacy(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
         if input.len() < 2 {
-            return input;
+            return Ok(input);
         }
 
         let (&delim, bytes) = input.split_first().unwrap();
-        log::info!("Found delim {delim}");
+        log::info!("Found legacy delim {delim}");
 
         let mut output: Vec<u8> = vec![];
 
         let mut bytes = bytes.iter();
         while let Some(&b) = bytes.next() {
+            let cut_off = || ParseError::from("RLE data was cut off");
             if b == b'\\' {
-                output.push(*bytes.next().unwrap());
+                output.push(*bytes.next().ok_or_else(cut_off)?);
             } else if b == delim {
-                let mut count = *bytes.next().unwrap();
-                let byte_to_repeat = bytes.next().unwrap();
+                let mut count = *bytes.next().ok_or_else(cut_off)?;
+                let byte_to_repeat = bytes.next().ok_or_else(cut_off)?;
+                // The old encoder counted the escaping backslashes as part of the run
                 if *byte_to_repeat == b'\\' {
                     count = count.div(2);
                 }
-                (0..count)
-                    .into_iter()
-                    .for_each(|_| output.push(*byte_to_repeat));
+                limits.check("RLE", output.len() + count as usize, 1)?;
+                output.extend(std::iter::repeat_n(*byte_to_repeat, count as usize));
             } else {
                 output.push(b);
             }
         }
 
-        output
+        Ok(output)
     }
 }
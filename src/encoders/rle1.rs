@@ -0,0 +1,78 @@
+/*
+    This is bzip2's initial run-length stage, meant to run *before* the BWT.
+
+    Suffix sorting is at its slowest on long runs of a single byte (zero-filled disk images, padding, etc.),
+    so this stage caps every run at 4 bytes + 1 count byte:
+
+    aaaaaaa -> aaaa(3)      aaaa -> aaaa(0)      aaa -> aaa
+
+    Count bytes range over 0..=251, so runs longer than 255 are simply split into several of these.
+    Unlike Rle, this stage is not trying to win compression by itself. Its job is to keep the BWT input sane.
+*/
+
+use super::{encoder::ParseError, limits::DecodeLimits};
+
+// Number of consecutive identical bytes written out before a count byte follows
+const RUN_LENGTH: usize = 4;
+
+// The longest run that fits in RUN_LENGTH bytes + a single count byte
+const MAX_RUN: usize = RUN_LENGTH + 251;
+
+pub struct Rle1;
+
+impl Rle1 {
+    pub fn encode(input: Vec<u8>) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::with_capacity(input.len());
+        let mut runs = 0;
+
+        let mut index = 0;
+        while index < input.len() {
+            let byte = input[index];
+            let run = input[index..]
+                .iter()
+                .take(MAX_RUN)
+                .take_while(|&&b| b == byte)
+                .count();
+
+            if run < RUN_LENGTH {
+                output.extend(std::iter::repeat_n(byte, run));
+            } else {
+                output.extend(std::iter::repeat_n(byte, RUN_LENGTH));
+                output.push((run - RUN_LENGTH) as u8);
+                runs += 1;
+            }
+            index += run;
+        }
+
+        log::info!(
+            "Encoding: Capped {runs} runs, {} bytes -> {} bytes",
+            input.len(),
+            output.len()
+        );
+        output
+    }
+
+    pub fn decode(input: Vec<u8>, limits: &DecodeLimits) -> Result<Vec<u8>, ParseError> {
+        let mut output: Vec<u8> = Vec::with_capacity(input.len());
+        let mut bytes = input.iter();
+
+        let mut last_byte = None;
+        let mut count = 0;
+        while let Some(&b) = bytes.next() {
+            output.push(b);
+
+            count = if last_byte == Some(b) { count + 1 } else { 1 };
+            last_byte = Some(b);
+
+            if count == RUN_LENGTH {
+                let extra = *bytes.next().ok_or(ParseError::from("RLE1 run count was cut off"))?;
+                limits.check("RLE1", output.len() + extra as usize, 1)?;
+                output.extend(std::iter::repeat_n(b, extra as usize));
+                // Runs longer than MAX_RUN continue with the same byte, so counting restarts here
+                last_byte = None;
+            }
+        }
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,122 @@
+use clap::ValueEnum;
+
+use crate::{
+    archive,
+    encoders::{
+        bwt::Bwt,
+        delta::Delta,
+        encoder::Tokens,
+        huff::Huff,
+        inv_freq::If,
+        limits::DecodeLimits,
+        mtf::Mtf,
+        rle::Rle,
+        rle1::Rle1,
+    },
+};
+
+/*
+    Fuzz entry points: every decoder has to cope with whatever bytes it is handed, returning an error for garbage
+    instead of panicking. Since a .pkz can come from anywhere, so can the input of every stage.
+
+    The hidden `fuzz <target>` subcommand feeds stdin to one of them (or all of them), so the binary AFL++ builds
+    with `cargo afl build` can be fuzzed as it is. The dev profile aborts on panic, which AFL counts as a crash:
+
+        cargo afl build
+        cargo afl fuzz -i examples -o fuzz-out target/debug/compression-v2 fuzz huff
+
+    The tests run the same entry points over random and mutated data on every `cargo test`.
+
+    Everything decodes under LIMITS, like a service decompressing uploads would. A header claiming a few gigabytes
+    is exactly what a fuzzer comes up with, and it should get an error back rather than run out of memory.
+*/
+
+/// Small enough for thousands of runs a second, large enough for anything in examples/
+pub const LIMITS: DecodeLimits = DecodeLimits {
+    max_output_size: 1_000_000,
+    max_memory: 64_000_000,
+};
+
+/// A decoder to fuzz
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FuzzTarget {
+    Bwt,
+    Mtf,
+    Rle,
+    Huff,
+    Rle1,
+    If,
+    Delta,
+    /// A whole .pkz container, header and all
+    Tokens,
+    /// A .pka archive's index and members
+    Archive,
+    /// Every one of the above
+    All,
+}
+
+impl FuzzTarget {
+    /// Every target but `All`
+    pub const DECODERS: [FuzzTarget; 9] = [
+        Self::Bwt,
+        Self::Mtf,
+        Self::Rle,
+        Self::Huff,
+        Self::Rle1,
+        Self::If,
+        Self::Delta,
+        Self::Tokens,
+        Self::Archive,
+    ];
+}
+
+/// Decodes `data` with `target`, along with reading its header like --list does.
+/// Errors are fine, it only has to come back without panicking.
+pub fn run(target: FuzzTarget, data: &[u8]) {
+    match target {
+        FuzzTarget::Bwt => {
+            let _ = Bwt::describe(data);
+            let _ = Bwt::decode(data.to_vec(), &LIMITS);
+        }
+        FuzzTarget::Mtf => {
+            let _ = Mtf::describe(data);
+            let _ = Mtf::alphabet(data);
+            let _ = Mtf::decode(data.to_vec(), &LIMITS);
+        }
+        FuzzTarget::Rle => {
+            let _ = Rle::describe(data);
+            let _ = Rle::decode(data.to_vec(), &LIMITS);
+        }
+        FuzzTarget::Huff => {
+            let _ = Huff::describe(data);
+            let _ = Huff::code_lengths(data);
+            let _ = Huff::trees(data, &LIMITS);
+        }
+        FuzzTarget::Rle1 => {
+            let _ = Rle1::decode(data.to_vec(), &LIMITS);
+        }
+        FuzzTarget::If => {
+            let _ = If::describe(data);
+            let _ = If::decode(data.to_vec(), &LIMITS);
+        }
+        FuzzTarget::Delta => {
+            // The stride comes from the container header, which is just as untrusted
+            let stride = data.first().copied().unwrap_or(1);
+            let _ = Delta::decode(data.to_vec(), stride);
+        }
+        FuzzTarget::Tokens => {
+            let _ = Tokens::inspect(data, &LIMITS);
+            let mut tokens = Tokens::new(vec![]);
+            tokens.limits = LIMITS;
+            let _ = tokens.decompress(data.to_vec());
+        }
+        FuzzTarget::Archive => {
+            if let Ok(entries) = archive::read_index(data) {
+                for entry in entries.iter() {
+                    let _ = archive::read_member(data, entry, &LIMITS);
+                }
+            }
+        }
+        FuzzTarget::All => FuzzTarget::DECODERS.iter().for_each(|&t| run(t, data)),
+    }
+}
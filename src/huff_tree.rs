@@ -0,0 +1,190 @@
+use std::{error::Error, fs};
+
+use crate::{
+    encoders::{
+        encoder::{Encoding, Tokens},
+        huff::Huff,
+        huff_helper::{HuffmanNode, Node},
+        limits::DecodeLimits,
+    },
+    utils::*,
+};
+
+/*
+    --huff-dot and --huff-codes: the Huffman tree(s) a file is coded with, for debugging Huffman headers.
+
+    What the Huffman stage writes is the same whether compress just wrote it or decompress is about to read it,
+    so both directions take the trees from there. They are rebuilt the way Huff::decode does it (build_tree from
+    the stored traversals, or from the code lengths with several tables), and every node's frequency is counted
+    from the symbols it decodes. With a single table, that is the very tree Huff::encode built from those counts.
+
+    With several tables, each one gets its own cluster in the graph and its own section in the code table.
+*/
+
+type Tree = Option<Box<HuffmanNode>>;
+
+/// Writes the Huffman tree(s) of the .pkz in `data` to whichever of `dot` and `codes` are given
+pub fn write(
+    data: &[u8],
+    dot: Option<&str>,
+    codes: Option<&str>,
+    limits: &DecodeLimits,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if dot.is_none() && codes.is_none() {
+        return Ok(());
+    }
+
+    let info = Tokens::inspect(data, limits)?;
+    // The outermost Huffman stage, in case there's more than one
+    let Some(index) = info
+        .pipeline
+        .iter()
+        .rposition(|e| matches!(e, Encoding::Huff { .. }))
+    else {
+        match info.pipeline.is_empty() {
+            true => log::warn!("The file is stored as-is, so there's no Huffman tree to write"),
+            false => log::warn!("The pipeline has no Huffman stage, so there's no tree to write"),
+        }
+        return Ok(());
+    };
+    let trees = Huff::trees(&Tokens::stage_output(data, index, limits)?, limits)?;
+
+    if let Some(path) = dot {
+        fs::write(path, to_dot(&trees))?;
+        log::info!("Wrote the Huffman tree to {path}");
+    }
+    if let Some(path) = codes {
+        fs::write(path, code_table(&trees))?;
+        log::info!("Wrote the Huffman code table to {path}");
+    }
+    Ok(())
+}
+
+/// A byte as hex, followed by its character if it is printable
+fn byte_label(byte: u8) -> String {
+    format!("{byte:#04x} {}", printable(byte)).trim_end().to_string()
+}
+
+/// A code as 0's and 1's
+fn format_code(path: &[u8]) -> String {
+    match path.is_empty() {
+        // A tree that is a single Leaf decodes without reading any bits
+        true => "(empty)".to_string(),
+        false => path.iter().map(|bit| bit.to_string()).collect(),
+    }
+}
+
+fn to_dot(trees: &[Tree]) -> String {
+    let mut lines = vec![
+        "digraph huffman {".to_string(),
+        "    node [fontname=\"monospace\"];".to_string(),
+    ];
+    for (table, root) in trees.iter().enumerate() {
+        let Some(root) = root else {
+            continue;
+        };
+        let indent = match trees.len() {
+            1 => "    ",
+            _ => "        ",
+        };
+        if trees.len() > 1 {
+            lines.push(format!("    subgraph cluster_{table} {{"));
+            lines.push(format!("        label=\"table {table}\";"));
+        }
+        let mut count = 0;
+        dot_nodes(root, table, &mut vec![], &mut count, indent, &mut lines);
+        if trees.len() > 1 {
+            lines.push("    }".to_string());
+        }
+    }
+    lines.push("}".to_string());
+    lines.join("\n") + "\n"
+}
+
+/// Adds `node` and everything under it to the graph, returning the name it goes by.
+/// Internal nodes show their id and frequency, Leaf nodes their byte, frequency and code.
+fn dot_nodes(
+    node: &HuffmanNode,
+    table: usize,
+    path: &mut Vec<u8>,
+    count: &mut usize,
+    indent: &str,
+    lines: &mut Vec<String>,
+) -> String {
+    // Ids only have to be unique within a table, names within the whole graph
+    let name = format!("t{table}_n{count}");
+    *count += 1;
+
+    match node.byte {
+        Node::Leaf(byte) => {
+            let label = byte_label(byte).replace('\\', "\\\\").replace('"', "\\\"");
+            lines.push(format!(
+                "{indent}{name} [shape=box, label=\"{label}\\nfreq {}\\ncode {}\"];",
+                node.frequency,
+                format_code(path)
+            ));
+        }
+        Node::Internal(id) => {
+            lines.push(format!(
+                "{indent}{name} [shape=circle, label=\"#{id}\\n{}\"];",
+                node.frequency
+            ));
+            for (bit, child) in [(0, &node.left), (1, &node.right)] {
+                if let Some(child) = child {
+                    path.push(bit);
+                    let child_name = dot_nodes(child, table, path, count, indent, lines);
+                    path.pop();
+                    lines.push(format!("{indent}{name} -> {child_name} [label=\"{bit}\"];"));
+                }
+            }
+        }
+    }
+    name
+}
+
+/// Every Leaf under `node` as (byte, frequency, code)
+fn leaves(node: &Tree, path: &mut Vec<u8>, output: &mut Vec<(u8, usize, Vec<u8>)>) {
+    let Some(n) = node else {
+        return;
+    };
+    match n.byte {
+        Node::Leaf(byte) => output.push((byte, n.frequency, path.clone())),
+        Node::Internal(_) => {
+            for (bit, child) in [(0, &n.left), (1, &n.right)] {
+                path.push(bit);
+                leaves(child, path, output);
+                path.pop();
+            }
+        }
+    }
+}
+
+fn code_table(trees: &[Tree]) -> String {
+    let mut lines = vec![];
+    for (table, root) in trees.iter().enumerate() {
+        let mut codes = vec![];
+        leaves(root, &mut vec![], &mut codes);
+        // Shortest codes first, which is also the order canonical codes are assigned in
+        codes.sort_by_key(|(byte, _, path)| (path.len(), *byte));
+
+        let bits: usize = codes.iter().map(|(_, freq, path)| freq * path.len()).sum();
+        if table > 0 {
+            lines.push(String::new());
+        }
+        lines.push(format!(
+            "Table {table}: {} symbols, {bits} bits of data ({} bytes)",
+            codes.len(),
+            bits.div_ceil(8)
+        ));
+        lines.push(format!("  {:<10} {:>10} {:>7}  Code", "Byte", "Frequency", "Length"));
+        for (byte, freq, path) in codes.iter() {
+            lines.push(format!(
+                "  {:<10} {freq:>10} {:>7}  {}",
+                byte_label(*byte),
+                path.len(),
+                format_code(path)
+            ));
+        }
+    }
+    lines.join("\n") + "\n"
+}
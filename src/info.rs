@@ -0,0 +1,151 @@
+use colored::Colorize;
+
+use crate::{
+    encoders::{
+        encoder::{format_pipeline, ContainerInfo, Tokens},
+        limits::DecodeLimits,
+    },
+    utils::*,
+};
+
+/*
+    --list / info: shows what's inside .pkz files without writing anything.
+
+    Everything up to the pipeline comes straight from the container header. The stage headers are read by
+    `Tokens::inspect`, which has to peel off the outer stages to get to the inner ones (see there).
+    A file that can't be read or isn't a .pkz gets its error in place of its headers, and the rest still get listed.
+*/
+
+/// Prints the headers of every file in `paths`, either as a table or as a JSON array.
+/// Returns whether every file could be read.
+pub fn print(paths: &[String], json: bool, limits: &DecodeLimits) -> bool {
+    let reports: Vec<_> = paths.iter().map(|path| (path, inspect(path, limits))).collect();
+
+    if json {
+        let objects: Vec<String> = reports
+            .iter()
+            .map(|(path, report)| match report {
+                Ok((compressed, container)) => to_json(path, *compressed, container),
+                Err(e) => json_object(&[("file", json_string(path)), ("error", json_string(e))]),
+            })
+            .collect();
+        println!("[{}]", objects.join(","));
+    } else {
+        for (i, (path, report)) in reports.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            match report {
+                Ok((compressed, container)) => print_table(path, *compressed, container),
+                Err(e) => println!("{path}: {} ({e})", "FAILED".red().bold()),
+            }
+        }
+    }
+    reports.iter().all(|(_, report)| report.is_ok())
+}
+
+/// Reads the headers of `path`, along with its size
+fn inspect(path: &str, limits: &DecodeLimits) -> Result<(usize, ContainerInfo), String> {
+    let data = read_input(path).map_err(|e| e.to_string())?;
+    let container = Tokens::inspect(&data, limits).map_err(|e| e.reason().to_string())?;
+    Ok((data.len(), container))
+}
+
+fn print_table(path: &str, compressed: usize, container: &ContainerInfo) {
+    let info = &container.info;
+    let unknown = || "-".to_string();
+
+    println!("{path}");
+    println!(
+        "  Format:    {}",
+        match container.version {
+            Some(version) => format!(".pkz version {version}"),
+            None => "legacy .pkz".to_string(),
+        }
+    );
+    println!("  Name:      {}", info.name.clone().unwrap_or_else(unknown));
+    println!("  Mode:      {}", info.mode.map(|m| format!("{m:o}")).unwrap_or_else(unknown));
+    println!("  Modified:  {}", info.mtime.map(format_timestamp).unwrap_or_else(unknown));
+    match info.size {
+        Some(size) => println!(
+            "  Size:      {size} -> {compressed} bytes ({:.2}%)",
+            ratio(compressed, size as usize)
+        ),
+        None => println!("  Size:      ? -> {compressed} bytes"),
+    }
+    println!("  CRC-32:    {}", info.checksum.map(|c| format!("{c:08x}")).unwrap_or_else(unknown));
+    match container.pipeline.is_empty() {
+        true => println!("  Pipeline:  none (stored)"),
+        false => println!("  Pipeline:  {}", format_pipeline(&container.pipeline)),
+    }
+    if container.total_blocks > 0 {
+        println!(
+            "  Stored:    {} of {} blocks",
+            container.stored_blocks, container.total_blocks
+        );
+    }
+
+    if container.stages.is_empty() {
+        return;
+    }
+    println!();
+    println!("  {:<28} {:>10}  Header", "Stage", "Output");
+    for stage in container.stages.iter() {
+        let fields = match &stage.fields {
+            Some(fields) if fields.is_empty() => "-".to_string(),
+            Some(fields) => fields
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<String>>()
+                .join(", "),
+            None => "unreadable".to_string(),
+        };
+        println!(
+            "  {:<28} {:>10}  {fields}",
+            stage.encoding.to_string(),
+            stage.output_len
+        );
+    }
+}
+
+fn to_json(path: &str, compressed: usize, container: &ContainerInfo) -> String {
+    let info = &container.info;
+    let null = || "null".to_string();
+
+    let stages: Vec<String> = container
+        .stages
+        .iter()
+        .map(|stage| {
+            let header = match &stage.fields {
+                Some(fields) => {
+                    let fields: Vec<_> = fields
+                        .iter()
+                        .map(|(key, value)| (key.replace(' ', "_"), json_string(value)))
+                        .collect();
+                    json_object(&fields)
+                }
+                None => null(),
+            };
+            json_object(&[
+                ("stage", json_string(&stage.encoding.to_string())),
+                ("output_size", stage.output_len.to_string()),
+                ("header", header),
+            ])
+        })
+        .collect();
+
+    json_object(&[
+        ("file", json_string(path)),
+        ("version", container.version.map(|v| v.to_string()).unwrap_or_else(null)),
+        ("name", info.name.as_deref().map(json_string).unwrap_or_else(null)),
+        ("mode", info.mode.map(|m| m.to_string()).unwrap_or_else(null)),
+        ("mtime", info.mtime.map(|m| m.to_string()).unwrap_or_else(null)),
+        ("original_size", info.size.map(|s| s.to_string()).unwrap_or_else(null)),
+        ("compressed_size", compressed.to_string()),
+        ("crc32", info.checksum.map(|c| json_string(&format!("{c:08x}"))).unwrap_or_else(null)),
+        ("pipeline", json_string(&format_pipeline(&container.pipeline))),
+        ("stored_blocks", container.stored_blocks.to_string()),
+        ("total_blocks", container.total_blocks.to_string()),
+        ("stages", format!("[{}]", stages.join(","))),
+    ])
+}
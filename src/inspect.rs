@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, error::Error, fs, path::Path, str::FromStr};
+
+use colored::Colorize;
+
+use crate::{
+    encoders::{
+        encoder::{Encoding, Tokens},
+        huff::Huff,
+        limits::DecodeLimits,
+        mtf::Mtf,
+    },
+    utils::*,
+};
+
+/*
+    inspect: the stage headers of a round trip, to narrow a broken one down to a single stage.
+
+    Given a --dump-stages directory, both directions of every stage are lined up: their sizes, whether they match
+    (and where they first differ), and the header fields read from the front of each (BWT primary index,
+    MTF alphabet, RLE format or delimiter, Huffman code lengths...).
+    Decoding runs from the last stage to the first, so the first mismatch on that way down points at the stage
+    right above it: its own output matched, but undoing it didn't give back what it was given.
+
+    Given a .pkz, its stages are undone one at a time in memory instead, showing the header of each.
+*/
+
+// Bytes (or code lengths) per line when listing them
+const ROW_WIDTH: usize = 16;
+
+/// Inspects every --dump-stages directory or .pkz file in `paths`
+pub fn print(paths: &[String], limits: &DecodeLimits) -> Result<(), Box<dyn Error>> {
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        match Path::new(path).is_dir() {
+            true => print_dump(path)?,
+            false => print_container(path, &read_input(path)?, limits)?,
+        }
+    }
+    Ok(())
+}
+
+/// The header fields at the front of a stage's output, one per line,
+/// with the MTF alphabet and Huffman code lengths spelled out
+fn header_lines(encoding: &Encoding, output: &[u8]) -> Vec<String> {
+    let Some(fields) = encoding.describe(output) else {
+        return vec!["unreadable header".to_string()];
+    };
+    let mut lines: Vec<String> = fields.iter().map(|(key, value)| format!("{key}: {value}")).collect();
+
+    let rows = |items: Vec<String>| -> Vec<String> {
+        items
+            .chunks(ROW_WIDTH)
+            .map(|row| format!("  {}", row.join(" ")))
+            .collect()
+    };
+    match encoding {
+        Encoding::Mtf(_) => {
+            if let Some(alphabet) = Mtf::alphabet(output) {
+                lines.push("alphabet:".to_string());
+                lines.extend(rows(alphabet.iter().map(|b| format!("{b:02x}")).collect()));
+            }
+        }
+        Encoding::Huff { .. } => {
+            for (table, lengths) in Huff::code_lengths(output).unwrap_or_default().iter().enumerate() {
+                lines.push(format!("code lengths (table {table}, byte=length):"));
+                lines.extend(rows(
+                    (0..256)
+                        .filter(|&b| lengths[b] > 0)
+                        .map(|b| format!("{b:02x}={}", lengths[b]))
+                        .collect(),
+                ));
+            }
+        }
+        _ => {}
+    }
+    lines
+}
+
+fn print_headers(label: &str, encoding: &Encoding, output: &[u8]) {
+    println!("  {label}");
+    for line in header_lines(encoding, output) {
+        println!("      {line}");
+    }
+}
+
+fn print_container(path: &str, data: &[u8], limits: &DecodeLimits) -> Result<(), Box<dyn Error>> {
+    let (pipeline, outputs) = Tokens::stage_outputs(data, limits)?;
+    println!("{path}");
+    if pipeline.is_empty() {
+        println!("  Stored as-is, there are no stages to inspect");
+        return Ok(());
+    }
+
+    // From the outermost stage in, like decoding
+    for ((index, encoding), output) in pipeline.iter().enumerate().rev().zip(outputs) {
+        match output {
+            Ok(output) => print_headers(
+                &format!("{:02} {encoding} ({} bytes)", index + 1, output.len()),
+                encoding,
+                &output,
+            ),
+            Err(e) => println!("  {:02} {encoding}: {} ({})", index + 1, "couldn't be reached".red(), e.reason()),
+        }
+    }
+    Ok(())
+}
+
+/// Both directions of a stage, as dumped by --dump-stages
+#[derive(Default)]
+struct DumpedStage {
+    name: String,
+    encode: Option<Vec<u8>>,
+    decode: Option<Vec<u8>>,
+}
+
+fn print_dump(dir: &str) -> Result<(), Box<dyn Error>> {
+    // Files are named `{encode|decode}-{index}-{stage}.bin`, see `Tokens::dump_stage`
+    let mut stages: BTreeMap<usize, DumpedStage> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(stem) = path.file_name().and_then(|n| n.to_str()?.strip_suffix(".bin")) else {
+            continue;
+        };
+        let mut parts = stem.splitn(3, '-');
+        let (Some(direction), Some(Ok(index)), Some(name)) =
+            (parts.next(), parts.next().map(str::parse::<usize>), parts.next())
+        else {
+            continue;
+        };
+        let stage = stages.entry(index).or_default();
+        stage.name = name.to_string();
+        match direction {
+            "encode" => stage.encode = Some(fs::read(&path)?),
+            "decode" => stage.decode = Some(fs::read(&path)?),
+            _ => {}
+        }
+    }
+    if stages.is_empty() {
+        return Err(format!("{dir} has no files written by --dump-stages").into());
+    }
+
+    let size = |data: &Option<Vec<u8>>| data.as_ref().map_or("-".to_string(), |d| d.len().to_string());
+    println!("{dir}");
+    println!("  {:<12} {:>10} {:>10}  Match", "Stage", "Encode", "Decode");
+    let mut first_mismatch = None;
+    for (&index, stage) in stages.iter().rev() {
+        let matches = match (&stage.encode, &stage.decode) {
+            (Some(encode), Some(decode)) => match first_difference(encode, decode) {
+                None => "yes".green().to_string(),
+                Some(offset) => {
+                    first_mismatch.get_or_insert((index, offset));
+                    format!("{} at offset {offset}", "differs".red().bold())
+                }
+            },
+            _ => "-".to_string(),
+        };
+        println!(
+            "  {:<12} {:>10} {:>10}  {matches}",
+            format!("{index:02} {}", stage.name),
+            size(&stage.encode),
+            size(&stage.decode)
+        );
+    }
+
+    println!();
+    let top = *stages.keys().last().unwrap();
+    match first_mismatch {
+        None if stages.values().all(|s| s.encode.is_some() && s.decode.is_some()) => {
+            println!("  Every stage round trips")
+        }
+        None => println!("  Only one direction was dumped, so there's nothing to compare"),
+        Some((index, offset)) if index == top => println!(
+            "  What was decoded isn't what was encoded (from offset {offset}), the .pkz itself doesn't match this run"
+        ),
+        Some((index, offset)) => println!(
+            "  {:02} {} doesn't invert: undoing it gave back different bytes than it was given (from offset {offset})",
+            index + 1,
+            stages.get(&(index + 1)).map_or("", |s| s.name.as_str())
+        ),
+    }
+
+    // Index 0 is the pipeline's input, which has no header
+    for (&index, stage) in stages.iter().filter(|(&index, _)| index > 0) {
+        let Ok(encoding) = Encoding::from_str(&stage.name) else {
+            continue;
+        };
+        println!();
+        match (&stage.encode, &stage.decode) {
+            (Some(encode), Some(decode)) if encode != decode => {
+                print_headers(&format!("{index:02} {} (encode)", stage.name), &encoding, encode);
+                print_headers(&format!("{index:02} {} (decode)", stage.name), &encoding, decode);
+            }
+            (Some(data), _) | (None, Some(data)) => {
+                print_headers(&format!("{index:02} {}", stage.name), &encoding, data)
+            }
+            (None, None) => {}
+        }
+    }
+    Ok(())
+}
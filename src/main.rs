@@ -1,57 +1,231 @@
 // #![allow(unused)]
+mod analyze;
+mod archive;
+mod batch;
+mod bench;
 mod encoders;
+mod fuzz;
+mod huff_tree;
+mod info;
+mod inspect;
+mod stats;
+#[cfg(test)]
 mod tests;
 mod utils;
+mod verify;
 
-use encoder::{Encoding, Tokens};
+use stats::Report;
+use encoder::{format_pipeline, Tokens};
+use limits::DecodeLimits;
 use encoders::*;
+use log::LevelFilter;
 use simple_logger::SimpleLogger;
 use utils::*;
 
-use std::{error::Error, io::Write, process::exit};
+use std::{error::Error, io::Write, path::Path, process::exit, time::SystemTime};
 
-use clap::Parser;
 use colored::Colorize;
 use sha256::digest;
 
-fn main() -> Result<(), Box<dyn Error>> {
+// Log targets of the stages, of --auto's search and of the thread pool's summary
+const STAGES_TARGET: &str = concat!(module_path!(), "::encoders");
+const AUTO_TARGET: &str = concat!(module_path!(), "::encoders::auto");
+const BATCH_TARGET: &str = concat!(module_path!(), "::batch");
+
+fn main() {
+    // Returning the error from main would print it with Debug
+    if let Err(e) = run() {
+        eprintln!("Error: {e}");
+        exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
     // Parse CLI Args
-    let args = Args::parse();
+    let args = Args::parse_with_levels();
+
+    // --list, --test, info, inspect, bench and analyze print to stdout as well
+    let listing = args.list
+        || args.test
+        || matches!(
+            args.command,
+            Some(
+                Command::Info { .. }
+                    | Command::Inspect { .. }
+                    | Command::Bench { .. }
+                    | Command::Analyze { .. }
+                    | Command::Fuzz { .. }
+            )
+        );
+
+    // If printing to stdout OR if quiet option enabled, then don't enable log printing.
+    // Stdin always decodes to stdout, even when it's one of several inputs.
+    if !args.quiet && !listing && (args.command.is_some() || !(args.to_stdout() || args.reads_stdin())) {
+        // The stages log every step. That's noise across several files, or the samples --auto tries, so only their
+        // warnings and errors get through there. Set once up front, as the stages may run on a thread pool.
+        let mut logger = SimpleLogger::new().without_timestamps();
+        if args.auto {
+            logger = logger
+                .with_module_level(STAGES_TARGET, LevelFilter::Warn)
+                .with_module_level(AUTO_TARGET, LevelFilter::Info);
+        }
+        if args.command.is_none() && args.batch() {
+            // The summary at the end takes the place of the single-file statistics
+            logger = logger
+                .with_level(LevelFilter::Warn)
+                .with_module_level(BATCH_TARGET, LevelFilter::Info);
+        }
+        logger.init()?;
+    }
+
+    // Archive mode takes over entirely
+    match &args.command {
+        Some(Command::Create { archive, paths }) => return archive::create(archive, paths, &args),
+        Some(Command::Extract { archive, directory }) => {
+            return archive::extract(archive, directory, args.force, &args.decode_limits(false))
+        }
+        Some(Command::List { archive }) => return archive::list(archive),
+        Some(Command::Info { files, json }) => {
+            if !info::print(files, *json, &args.decode_limits(files.iter().any(|f| f == "-"))) {
+                exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Inspect { paths }) => return inspect::print(paths, &args.decode_limits(paths.iter().any(|p| p == "-"))),
+        Some(Command::Bench { dir, with, csv }) => return bench::run(dir, with, csv.as_deref()),
+        Some(Command::Analyze { files }) => return analyze::print(files),
+        Some(Command::Fuzz { target }) => {
+            fuzz::run(*target, &read_input("-")?);
+            return Ok(());
+        }
+        None => {}
+    }
+    let inputs = args.inputs()?;
 
-    // If printing to stdout OR if quiet option enabled, then don't enable log printing
-    if !args.stdout && !args.quiet {
-        SimpleLogger::new().without_timestamps().init()?;
+    if args.list {
+        // Every file gets listed, even after one has failed
+        if !info::print(&inputs, args.json, &args.decode_limits(inputs.iter().any(|i| i == "-"))) {
+            exit(1);
+        }
+        return Ok(());
     }
+    if args.test {
+        // Every file gets tested, even after one has failed
+        let mut passed = true;
+        for input in inputs.iter() {
+            passed &= verify::test(input, &args.decode_limits(input == "-"));
+        }
+        if !passed {
+            exit(1);
+        }
+        return Ok(());
+    }
+
+    let Some(input_path) = inputs.first() else {
+        log::warn!("No files to work on");
+        return Ok(());
+    };
+
+    // Several files get spread over a thread pool, with a summary at the end
+    if args.batch() {
+        if args.output.is_some() || args.stdout {
+            return Err("-o and -s only work with a single input file".into());
+        }
+        if args.huff_dot.is_some() || args.huff_codes.is_some() || args.dump_stages.is_some() {
+            return Err("--huff-dot, --huff-codes and --dump-stages only work with a single input file".into());
+        }
+        if !batch::run(&inputs, &args) {
+            exit(1);
+        }
+        return Ok(());
+    }
+
+    let report = process(input_path, &args).map_err(|e| e as Box<dyn Error>)?;
+
+    let stage_table = report.stage_report.table().join("\n        ");
+    if args.decompress {
+        log::info!("\n\n        {stage_table}\n");
+    } else {
+        // Print statistics & results
+        let Report {
+            input_size,
+            output_size,
+            ref output,
+            ref pipeline,
+            ..
+        } = report;
+
+        let percent = 100.0 - ratio(output_size, input_size);
+
+        let pipeline = match pipeline.is_empty() {
+            true => "none (stored)".to_string(),
+            false => format_pipeline(pipeline),
+        };
+        let level = match (args.auto, args.level()) {
+            (true, _) => format!("Pipeline (picked by --auto): {pipeline}"),
+            (false, Some(level)) => format!("Compression level: {level} ({pipeline})"),
+            (false, None) => format!("Pipeline: {pipeline}"),
+        };
+
+        log::info!(
+            r#"
+    Size (Bytes):
+
+        {} - {input_size} bytes
+        {output} - {output_size} bytes
+
+    {level}
+
+        {stage_table}
+
+    Total compression: {:0.2}%
+
+    {}
+    "#,
+            input_path,
+            percent,
+            format!(
+                "The output file is {:0.2}% of its original size!",
+                100.0 - percent
+            )
+            .bold(),
+        );
+    }
+    Ok(())
+}
+
+/// Compresses or decompresses a single file (or stdin, for `-`) according to `args`
+fn process(input_path: &str, args: &Args) -> Result<Report, Box<dyn Error + Send + Sync>> {
+    let time = SystemTime::now();
+    let from_stdin = input_path == "-";
 
     // Get input data. Hash it for decode verification later.
-    let input_data = std::fs::read(&args.input_path)?;
+    let input_data = read_input(input_path)?;
     let input_size = input_data.len();
     let original_sha256 = digest(&input_data);
 
-    // Define the Encoding pipeline
-    let pipeline = match args.pipeline {
-        Some(e) => {
-            use Encoding::*;
-            e.iter().map(|s| {
-                match s.to_uppercase().as_str() {
-                    "BWT" => Bwt,
-                    "MTF" => Mtf,
-                    "RLE" => Rle,
-                    "HUFF" => Huff,
-                    _ => panic!("Found an unexpected Encoding value with the --pipeline option. Use --help for more info"),
-                }
-            }).collect()
-        }
-        None => {
-            use Encoding::*;
-            vec![Bwt, Mtf, Rle, Huff]
-        }
+    // Define the Encoding pipeline. When decompressing, it comes from the header instead.
+    let pipeline = match args.decompress {
+        true => vec![],
+        false => args.resolve_pipeline(&input_data),
     };
 
-    let mut compressor = Tokens::new(pipeline.clone());
+    let mut compressor = Tokens::new(pipeline);
+    if let Some(dir) = &args.dump_stages {
+        std::fs::create_dir_all(dir)?;
+        compressor.dump_dir = Some(dir.into());
+    }
+    compressor.paranoid = args.paranoid;
+    // Only what we're handed needs limits, not what -c decodes back after compressing it ourselves
+    if args.decompress {
+        compressor.limits = args.decode_limits(from_stdin);
+    }
+    let huff_dot = args.huff_dot.as_deref();
+    let huff_codes = args.huff_codes.as_deref();
 
     // Declare output data, which will vary & change based on argument flags
     let output_data;
+    let stage_report;
     let mut output_file;
     enum OutputFile {
         File(String),
@@ -60,47 +234,88 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     match args.decompress {
         true => {
+            // The Huffman tree is read from the .pkz, before decompressing takes it over
+            huff_tree::write(&input_data, huff_dot, huff_codes, &compressor.limits)?;
+
             // If decompressing, then verify file ends with .pkz, then trim that to get output path
-            if let Some(output_path) = args.input_path.strip_suffix(".pkz") {
-                output_file = OutputFile::File(output_path.to_string());
-                output_data = compressor.decompress(input_data);
+            if from_stdin {
+                // There's no path to name the output after, so it goes to stdout (see below)
+                (output_data, stage_report) = compressor.decompress_with_report(input_data)?;
+                output_file = OutputFile::Stdout;
+            } else if let Some(output_path) = input_path.strip_suffix(".pkz") {
+                (output_data, stage_report) = compressor.decompress_with_report(input_data)?;
+
+                // Unless -n was passed, the original name (if stored) replaces the trimmed one, next to the .pkz
+                let stored_name = match args.no_name {
+                    true => None,
+                    false => compressor.info.name.as_deref().and_then(|n| Path::new(n).file_name()),
+                };
+                output_file = match stored_name {
+                    Some(name) => OutputFile::File(
+                        Path::new(input_path).with_file_name(name).to_string_lossy().into_owned(),
+                    ),
+                    None => OutputFile::File(output_path.to_string()),
+                };
             } else {
                 log::error!("This program expects a .pkz file when decompressing!");
-                panic!("This program expects a .pkz file when decompressing!");
+                return Err(format!("{input_path} isn't a .pkz file").into());
             }
         }
         false => {
-            // If compressing, simply append .pkz to input path
-            log::info!("Using Encoding Pipeline: {:?}", &pipeline);
-            output_file = OutputFile::File(format!("{}.pkz", args.input_path));
-            output_data = compressor.compress(input_data);
+            // If compressing, simply append .pkz to input path. Stdin has no path, so it goes to stdout.
+            log::info!("Using Encoding Pipeline: {}", format_pipeline(compressor.pipeline()));
+            output_file = match from_stdin {
+                true => OutputFile::Stdout,
+                false => OutputFile::File(format!("{input_path}.pkz")),
+            };
+            if !args.no_name && !from_stdin {
+                compressor.info = read_file_info(input_path)?;
+            }
+            (output_data, stage_report) = compressor.compress_with_report(input_data);
+            if let Some(mismatch) = &stage_report.mismatch {
+                return Err(mismatch.to_string().into());
+            }
+            // What we just wrote ourselves, so it's fine to decode without limits
+            huff_tree::write(&output_data, huff_dot, huff_codes, &DecodeLimits::UNLIMITED)?;
         }
     }
 
     let output_size = output_data.len();
 
-    // If --stdout option was passed, then overwrite the output file
-    match args.stdout {
-        true => {
-            output_file = OutputFile::Stdout;
-        }
-        false => {}
+    // If --stdout option was passed (or the input came from stdin), then overwrite the output file
+    if args.to_stdout() {
+        output_file = OutputFile::Stdout;
+    } else if let Some(output) = &args.output {
+        output_file = OutputFile::File(output.clone());
     }
 
     match output_file {
         OutputFile::File(ref s) => {
+            if Path::new(s).exists() {
+                if !args.force {
+                    return Err(format!("{s} already exists, pass -f to overwrite it").into());
+                }
+                // Even -f can't make `-o` point back at the input, it would be gone before it was read
+                if !from_stdin && Path::new(s).canonicalize()? == Path::new(input_path).canonicalize()? {
+                    return Err(format!("{s} is the input file, refusing to overwrite it").into());
+                }
+            }
             std::fs::write(s.as_str(), &output_data)?;
+            if args.decompress && !args.no_name {
+                restore_file_info(s, &compressor.info)?;
+            }
         }
         OutputFile::Stdout => {
             std::io::stdout().write_all(&output_data)?;
         }
     };
 
+    let mut verified = None;
     if args.check {
         let decoded = match args.decompress {
             true => compressor.compress(output_data),
-            false => compressor.decompress(output_data),
-        }; 
+            false => compressor.decompress(output_data)?,
+        };
 
         let new_sha256 = digest(&decoded);
 
@@ -109,47 +324,42 @@ fn main() -> Result<(), Box<dyn Error>> {
                 "Decode: {}. File decodes back to original.",
                 "Success".green().bold()
             );
-            exit(0);
         } else {
             log::error!(
                 "Decode: {}. File does not decode back to original.",
                 "Failed".red().bold()
             );
-            panic!(
-                "Decode: {}. File does not decode back to original.",
-                "Failed".red().bold()
-            )
         }
+        verified = Some(original_sha256 == new_sha256);
     }
 
-    if !args.decompress {
-        // Print statistics & results
-        let output_path = match output_file {
-            OutputFile::File(ref s) => s,
-            OutputFile::Stdout => "stdout",
-        };
-
-        let percent = (1.0 - output_size as f32 / input_size as f32) * 100.0;
-
-        log::info!(
-            r#"
-    Size (Bytes):
-
-        {} - {input_size} bytes
-        {output_path} - {output_size} bytes
+    let report = Report {
+        input: input_path.to_string(),
+        output: match output_file {
+            OutputFile::File(ref s) => s.clone(),
+            OutputFile::Stdout => "stdout".to_string(),
+        },
+        decompressed: args.decompress,
+        input_size,
+        output_size,
+        pipeline: match stage_report.stored {
+            true => vec![],
+            false => compressor.pipeline().to_vec(),
+        },
+        stage_report,
+        verified,
+        elapsed: time.elapsed().unwrap_or_default(),
+    };
+    stats::emit(&report, args)?;
+    if verified == Some(false) {
+        return Err(format!("{input_path} does not decode back to the original").into());
+    }
 
-    Total compression: {:0.2}%
-    
-    {}
-    "#,
-            args.input_path,
-            percent,
-            format!(
-                "The output file is {:0.2}% of its original size!",
-                100.0 - percent
-            )
-            .bold(),
-        );
+    // Only once the output is safely written (and checked, with -c). Output to stdout always keeps the input, like gzip -c.
+    if args.rm && !from_stdin && matches!(output_file, OutputFile::File(_)) {
+        log::info!("Removing {input_path}");
+        std::fs::remove_file(input_path)?;
     }
-    Ok(())
+
+    Ok(report)
 }
@@ -0,0 +1,111 @@
+use std::{
+    fs,
+    io::{self, Write},
+    sync::Mutex,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+
+use crate::{
+    encoders::encoder::{format_pipeline, Encoding, PipelineReport},
+    utils::*,
+};
+
+/*
+    --stats-format json: one JSON object per input file, for dashboards and scripts rather than people.
+
+    Objects go to stderr (stdout may well be the compressed data), or get appended to --stats-file,
+    one per line, so a file can collect many runs. With several inputs, each one gets its own line
+    as soon as it is done, so they may come out of order.
+*/
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// The usual summary in the log
+    #[default]
+    Text,
+    /// A JSON object per file, on stderr or in --stats-file
+    Json,
+}
+
+/// What happened to a single input
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub input: String,
+    /// Output path, or "stdout"
+    pub output: String,
+    pub decompressed: bool,
+    pub input_size: usize,
+    pub output_size: usize,
+    /// Pipeline written to the container (read from the header when decompressing).
+    /// Empty if the input was stored as-is, whatever pipeline was asked for.
+    pub pipeline: Vec<Encoding>,
+    /// Size, time and entropy of every stage that ran
+    pub stage_report: PipelineReport,
+    /// Whether the output decoded back to the input, None without -c
+    pub verified: Option<bool>,
+    pub elapsed: Duration,
+}
+
+// Keeps lines from different threads from ending up mixed together in the stats file
+static STATS_FILE: Mutex<()> = Mutex::new(());
+
+/// Writes `report` out in the format asked for by `args`. The text format is logged elsewhere, so that does nothing.
+pub fn emit(report: &Report, args: &Args) -> io::Result<()> {
+    if args.stats_format != StatsFormat::Json && args.stats_file.is_none() {
+        return Ok(());
+    }
+    let line = to_json(report);
+    match &args.stats_file {
+        Some(path) => {
+            let _lock = STATS_FILE.lock().unwrap();
+            let mut file = fs::File::options().create(true).append(true).open(path)?;
+            writeln!(file, "{line}")
+        }
+        None => writeln!(io::stderr(), "{line}"),
+    }
+}
+
+fn to_json(report: &Report) -> String {
+    let millis = |d: Duration| format!("{:.3}", d.as_secs_f64() * 1000.0);
+
+    let stages: Vec<String> = report
+        .stage_report
+        .stages
+        .iter()
+        .map(|stage| {
+            json_object(&[
+                ("stage", json_string(&stage.encoding.to_string())),
+                ("input_size", stage.input_len.to_string()),
+                ("output_size", stage.output_len.to_string()),
+                ("time_ms", millis(stage.elapsed)),
+                ("input_entropy", format!("{:.4}", stage.input_entropy)),
+                ("output_entropy", format!("{:.4}", stage.output_entropy)),
+            ])
+        })
+        .collect();
+
+    json_object(&[
+        ("input", json_string(&report.input)),
+        ("output", json_string(&report.output)),
+        (
+            "mode",
+            json_string(match report.decompressed {
+                true => "decompress",
+                false => "compress",
+            }),
+        ),
+        ("input_size", report.input_size.to_string()),
+        ("output_size", report.output_size.to_string()),
+        ("ratio", format!("{:.4}", ratio(report.output_size, report.input_size))),
+        ("pipeline", json_string(&format_pipeline(&report.pipeline))),
+        ("stages", format!("[{}]", stages.join(","))),
+        ("stored", report.stage_report.stored.to_string()),
+        ("time_ms", millis(report.elapsed)),
+        (
+            "verified",
+            report.verified.map_or("null".to_string(), |v| v.to_string()),
+        ),
+    ])
+}
@@ -1,5 +1,441 @@
-#[cfg(test)]
+use crate::{
+    bench::BenchPipeline,
+    encoders::{
+        bwt::Bwt,
+        delta::Delta,
+        encoder::{check_pipeline, default_pipeline, level_preset, Encoding, Tokens},
+        huff::Huff,
+        inv_freq::If,
+        limits::DecodeLimits,
+        mtf::{Mtf, MtfVariant},
+        rle::Rle,
+        rle1::Rle1,
+    },
+    fuzz::{self, FuzzTarget},
+    utils::{push_varint, Args, Command},
+};
 
-mod tests {
+/*
+    Round trips every stage, and whole pipelines, over the inputs most likely to trip them up: nothing at all,
+    a single byte, every byte value, long runs (around the 255 mark in particular), and data full of the bytes
+    the formats use as markers and delimiters ('\', '|', '#', 0xFF). Then the fuzz entry points get random and
+    mutated data, and headers with huge length fields, which may fail to decode but must never panic
+    (or run out of memory, see fuzz::LIMITS).
 
+    Everything random comes from a small generator with a fixed seed, so a failure always reproduces.
+*/
+
+/// xorshift64*, which is plenty for test data
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number from 0 to `n` - 1
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+
+    /// Bytes picked from `alphabet`, in runs of random length up to `max_run`
+    fn runs(&mut self, len: usize, alphabet: &[u8], max_run: usize) -> Vec<u8> {
+        let mut output = vec![];
+        while output.len() < len {
+            let byte = alphabet[self.below(alphabet.len())];
+            let run = 1 + self.below(max_run);
+            output.extend(std::iter::repeat_n(byte, run.min(len - output.len())));
+        }
+        output
+    }
+}
+
+fn edge_cases() -> Vec<(&'static str, Vec<u8>)> {
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+
+    // Every run length around the single-byte limits, with the byte changing in between
+    let mut run_lengths = vec![];
+    for (i, len) in [1, 2, 3, 4, 5, 127, 128, 254, 255, 256, 257, 510, 511, 512, 1000].iter().enumerate() {
+        run_lengths.extend(std::iter::repeat_n(b"ab"[i % 2], *len));
+    }
+
+    vec![
+        ("empty", vec![]),
+        ("single byte", vec![b'a']),
+        ("single zero", vec![0]),
+        ("single 0xff", vec![0xFF]),
+        ("two bytes", b"ab".to_vec()),
+        ("all 256 bytes", (0..=255).collect()),
+        ("all 256 bytes backwards", (0..=255).rev().collect()),
+        ("all 256 bytes twice", (0..=255).chain(0..=255).collect()),
+        ("long run", vec![b'x'; 20_000]),
+        ("long run of zeros", vec![0; 20_000]),
+        ("run lengths", run_lengths),
+        ("backslashes", vec![b'\\'; 1000]),
+        ("backslash heavy", rng.runs(5000, b"\\\\\\a0", 6)),
+        ("delimiter heavy", rng.runs(5000, b"\\|#\xFF\x00", 300)),
+        ("markers only", b"\\|#\xFF\xFF\\\\||##\xFF".repeat(100)),
+        ("two symbols", rng.runs(5000, b"ab", 3)),
+        ("random", rng.bytes(5000)),
+        ("text", include_bytes!("../examples/small_example.txt").to_vec()),
+    ]
+}
+
+#[test]
+fn bwt_round_trips() {
+    for (name, data) in edge_cases() {
+        for block in [0, 1, 7, 1000] {
+            let decoded = Bwt::decode(Bwt::encode(data.clone(), block), &DecodeLimits::default());
+            assert_eq!(decoded.unwrap(), data, "{name} with a block of {block}");
+        }
+    }
+}
+
+#[test]
+fn mtf_round_trips() {
+    for (name, data) in edge_cases() {
+        for variant in [MtfVariant::Mtf, MtfVariant::Mtf1, MtfVariant::Mtf2, MtfVariant::Wfc] {
+            let decoded = Mtf::decode(Mtf::encode(data.clone(), variant), &DecodeLimits::default());
+            assert_eq!(decoded.unwrap(), data, "{name} with {variant}");
+        }
+    }
+}
+
+#[test]
+fn rle_round_trips() {
+    let limits = DecodeLimits::default();
+    for (name, data) in edge_cases() {
+        assert_eq!(Rle::decode(Rle::encode(data.clone()), &limits).unwrap(), data, "{name}");
+        assert_eq!(Rle1::decode(Rle1::encode(data.clone()), &limits).unwrap(), data, "{name} with RLE1");
+    }
+}
+
+#[test]
+fn huff_round_trips() {
+    for (name, data) in edge_cases() {
+        for tables in 1..=6 {
+            let decoded = Huff::decode(Huff::encode(data.clone(), tables), &DecodeLimits::default());
+            assert_eq!(decoded.unwrap(), data, "{name} with {tables} tables");
+        }
+    }
+}
+
+#[test]
+fn if_and_delta_round_trip() {
+    for (name, data) in edge_cases() {
+        assert_eq!(If::decode(If::encode(data.clone()), &DecodeLimits::default()).unwrap(), data, "{name}");
+        for stride in [1, 2, 4, 255] {
+            let decoded = Delta::decode(Delta::encode(data.clone(), stride), stride);
+            assert_eq!(decoded.unwrap(), data, "{name} with a stride of {stride}");
+        }
+    }
+}
+
+#[test]
+fn stage_params_are_checked() {
+    assert!("delta:stride=0".parse::<Encoding>().is_err());
+    assert!("huff:tables=7".parse::<Encoding>().is_err());
+    // The same goes for params read from a header
+    assert!(Encoding::from_parts(6, &[0]).is_none());
+    assert!(Encoding::from_parts(3, &[0]).is_none());
+    assert!(Delta::decode(b"data".to_vec(), 0).is_err());
+
+    // The header counts stages in a single byte
+    assert!(check_pipeline(&vec![Encoding::Rle; 255]).is_ok());
+    assert!(check_pipeline(&vec![Encoding::Rle; 256]).is_err());
+    assert!("rle ".repeat(256).trim().parse::<BenchPipeline>().is_err());
+}
+
+#[test]
+fn huff_trees_count_every_symbol() {
+    for (name, data) in edge_cases().into_iter().filter(|(_, d)| !d.is_empty()) {
+        for tables in [1, 3] {
+            let trees = Huff::trees(&Huff::encode(data.clone(), tables), &DecodeLimits::default()).unwrap();
+            let total: usize = trees.iter().flatten().map(|root| root.frequency).sum();
+            assert_eq!(total, data.len(), "{name} with {tables} tables");
+        }
+    }
+}
+
+#[test]
+fn tokens_round_trip() {
+    use Encoding::*;
+    let mut pipelines: Vec<Vec<Encoding>> = (1..=9).map(level_preset).collect();
+    pipelines.push(default_pipeline());
+    pipelines.push(vec![Rle1, Delta { stride: 2 }, Bwt { block: 0 }, If, Huff { tables: 2 }]);
+    pipelines.push(vec![]);
+
+    for (name, data) in edge_cases() {
+        for pipeline in pipelines.iter() {
+            let mut tokens = Tokens::new(pipeline.clone());
+            tokens.paranoid = true;
+            let (compressed, report) = tokens.compress_with_report(data.clone());
+            assert!(report.mismatch.is_none(), "{name} with {pipeline:?}: {:?}", report.mismatch);
+            assert!(Tokens::inspect(&compressed, &DecodeLimits::default()).is_ok(), "{name} with {pipeline:?}");
+
+            let decoded = Tokens::new(vec![]).decompress(compressed);
+            assert_eq!(decoded.unwrap(), data, "{name} with {pipeline:?}");
+        }
+    }
+}
+
+#[test]
+fn tokens_catch_corruption() {
+    let data = include_bytes!("../examples/small_example.txt").to_vec();
+    let compressed = Tokens::new(default_pipeline()).compress(data);
+
+    let mut rng = Rng(42);
+    for _ in 0..200 {
+        let mut corrupted = compressed.clone();
+        let offset = rng.below(corrupted.len());
+        corrupted[offset] ^= 1 << rng.below(8);
+        // The CRC-32 catches anything that still happens to decode
+        assert!(Tokens::new(vec![]).decompress(corrupted).is_err(), "bit flip at {offset}");
+    }
+}
+
+#[test]
+fn legacy_files_still_decode() {
+    // Written by the original encoder: an `[ids]|` header, then escape-delimited RLE and alphabet-first MTF
+    let input = include_bytes!("../examples/legacy/input.txt").to_vec();
+    let files: [(&str, &[u8]); 4] = [
+        ("rle", include_bytes!("../examples/legacy/rle.pkz")),
+        ("mtf", include_bytes!("../examples/legacy/mtf.pkz")),
+        ("bwt mtf rle huff", include_bytes!("../examples/legacy/bwt-mtf-rle-huff.pkz")),
+        ("bwt rle mtf rle huff", include_bytes!("../examples/legacy/bwt-rle-mtf-rle-huff.pkz")),
+    ];
+    for (pipeline, file) in files {
+        let decoded = Tokens::new(vec![]).decompress(file.to_vec());
+        assert_eq!(decoded.unwrap(), input, "{pipeline}");
+    }
+
+    // The stages on their own, past the 2 byte header
+    let limits = DecodeLimits::default();
+    assert_eq!(Rle::decode(files[0].1[2..].to_vec(), &limits).unwrap(), input);
+    assert_eq!(Mtf::decode(files[1].1[2..].to_vec(), &limits).unwrap(), input);
+}
+
+#[test]
+fn fuzz_random_input() {
+    let mut rng = Rng(0xDEAD_BEEF);
+    for i in 0..2000 {
+        let len = rng.below(400);
+        let mut data = rng.bytes(len);
+        // Half the time, start with something that looks like a header, to get past the first checks
+        if i % 2 == 0 && !data.is_empty() {
+            let prefixes: [&[u8]; 6] = [b"PKZ\x04", b"PKA", b"#", b"\xFF\xFF", b"\\", b"\x00\x00\x00\x00\x00\x00\x00"];
+            let prefix = prefixes[rng.below(prefixes.len())];
+            data.splice(0..0, prefix.iter().copied());
+        }
+        fuzz::run(FuzzTarget::All, &data);
+    }
+}
+
+#[test]
+fn fuzz_mutated_input() {
+    let mut rng = Rng(0x5EED);
+    for (_, data) in edge_cases().into_iter().filter(|(_, d)| d.len() <= 5000) {
+        let encoded = vec![
+            (FuzzTarget::Bwt, Bwt::encode(data.clone(), 0)),
+            (FuzzTarget::Bwt, Bwt::encode(data.clone(), 100)),
+            (FuzzTarget::Mtf, Mtf::encode(data.clone(), MtfVariant::Wfc)),
+            (FuzzTarget::Rle, Rle::encode(data.clone())),
+            (FuzzTarget::Rle1, Rle1::encode(data.clone())),
+            (FuzzTarget::Huff, Huff::encode(data.clone(), 1)),
+            (FuzzTarget::Huff, Huff::encode(data.clone(), 3)),
+            (FuzzTarget::If, If::encode(data.clone())),
+            (FuzzTarget::Tokens, Tokens::new(default_pipeline()).compress(data.clone())),
+        ];
+
+        for (target, valid) in encoded {
+            for _ in 0..20 {
+                let mut mutated = valid.clone();
+                match rng.below(4) {
+                    _ if mutated.is_empty() => mutated.push(rng.next() as u8),
+                    0 => {
+                        let offset = rng.below(mutated.len());
+                        mutated[offset] ^= 1 << rng.below(8);
+                    }
+                    1 => {
+                        let offset = rng.below(mutated.len());
+                        mutated[offset] = rng.next() as u8;
+                    }
+                    2 => mutated.truncate(rng.below(mutated.len())),
+                    _ => {
+                        let offset = rng.below(mutated.len());
+                        let len = 1 + rng.below(8);
+                        let extra = rng.bytes(len);
+                        mutated.splice(offset..offset, extra);
+                    }
+                }
+                fuzz::run(target, &mutated);
+            }
+        }
+    }
+}
+
+#[test]
+fn decode_limits_stop_bombs() {
+    // A single-symbol Huffman stream decodes without reading any bits, so its length is whatever the header says
+    let mut huff = Huff::encode(vec![b'a'; 100], 1);
+    let tree_len = u64::from_be_bytes(huff[..8].try_into().unwrap()) as usize;
+    huff[8 + 2 * tree_len..16 + 2 * tree_len].copy_from_slice(&(1u64 << 40).to_be_bytes());
+
+    // IF takes its length from the symbol counts, and the last symbol fills every position left
+    let mut inv_freq = vec![0, b'a'];
+    push_varint(&mut inv_freq, 1 << 40);
+
+    // Counts for any symbol but the last need the gaps to back them
+    let mut unbacked = vec![1, b'a'];
+    push_varint(&mut unbacked, 1 << 20);
+    unbacked.push(b'b');
+    push_varint(&mut unbacked, 1);
+
+    // A run count can be anything a varint holds
+    let mut rle = Rle::encode(vec![b'a'; 10]);
+    rle.pop();
+    push_varint(&mut rle, 1 << 40);
+
+    for (name, result) in [
+        ("Huffman", Huff::decode(huff, &fuzz::LIMITS)),
+        ("IF", If::decode(inv_freq, &fuzz::LIMITS)),
+        ("RLE", Rle::decode(rle, &fuzz::LIMITS)),
+    ] {
+        let error = result.expect_err(name).to_string();
+        assert!(error.contains("over the limit"), "{name}: {error}");
+    }
+    assert!(If::decode(unbacked, &DecodeLimits::default()).is_err());
+
+    // Whole files, against each of the limits. Anything smaller would just be stored.
+    let data = b"abracadabra ".repeat(200);
+    let compressed = Tokens::new(default_pipeline()).compress(data.clone());
+    assert!(!Tokens::inspect(&compressed, &DecodeLimits::default()).unwrap().pipeline.is_empty());
+    let decompress = |max_output_size, max_memory| {
+        let mut tokens = Tokens::new(vec![]);
+        tokens.limits = DecodeLimits {
+            max_output_size,
+            max_memory,
+        };
+        tokens.decompress(compressed.clone())
+    };
+    // Inner stages can come out a few bytes larger than the file itself, with their headers
+    assert_eq!(decompress(data.len() + 100, usize::MAX).unwrap(), data);
+    assert!(decompress(data.len() - 1, usize::MAX).is_err());
+    // The BWT needs far more than its output, which is all the others need
+    assert_eq!(decompress(usize::MAX, 200 * data.len()).unwrap(), data);
+    assert!(decompress(usize::MAX, 10 * data.len()).is_err());
+}
+
+#[test]
+fn oversized_headers_fail_cleanly() {
+    // A Huffman tree far larger than the 511 nodes any tree can have, every node leading deeper into it.
+    // Building it used to recurse once per node and overflow the stack.
+    let mut deep = 600_000u64.to_be_bytes().to_vec();
+    deep.extend([1, 0].repeat(600_000));
+    deep.extend(0u64.to_be_bytes());
+    // And one whose preorder and inorder together overflow a usize
+    let mut huge = u64::MAX.to_be_bytes().to_vec();
+    huge.extend(b"data");
+    for huff in [deep, huge] {
+        assert!(Huff::decode(huff.clone(), &fuzz::LIMITS).is_err());
+        assert!(Huff::describe(&huff).is_none());
+        assert!(Huff::code_lengths(&huff).is_none());
+        fuzz::run(FuzzTarget::All, &huff);
+    }
+
+    // A BWT block claiming more than the limits allow, and a container whose file info claims the same
+    let mut bwt = Bwt::encode(b"banana".to_vec(), 2);
+    bwt.truncate(2);
+    push_varint(&mut bwt, 1 << 40);
+    assert!(Bwt::decode(bwt, &fuzz::LIMITS).is_err());
+    let mut pkz = b"PKZ\x04\x08".to_vec();
+    push_varint(&mut pkz, 1 << 40);
+    pkz.extend([0, 0, 0]);
+    assert!(Tokens::inspect(&pkz, &fuzz::LIMITS).is_ok());
+    let mut tokens = Tokens::new(vec![]);
+    tokens.limits = fuzz::LIMITS;
+    assert!(tokens.decompress(pkz).is_err());
+
+    // Then every length field of every stage: a huge varint or u64 written over (or into) every position
+    // of a valid header. These may still decode to something, but must come back instead of aborting.
+    let data = b"abracadabra ".repeat(50);
+    let streams = [
+        (FuzzTarget::Bwt, Bwt::encode(data.clone(), 0)),
+        (FuzzTarget::Bwt, Bwt::encode(data.clone(), 100)),
+        (FuzzTarget::Mtf, Mtf::encode(data.clone(), MtfVariant::Mtf)),
+        (FuzzTarget::Rle, Rle::encode(data.clone())),
+        (FuzzTarget::Huff, Huff::encode(data.clone(), 1)),
+        (FuzzTarget::Huff, Huff::encode(data.clone(), 3)),
+        (FuzzTarget::Rle1, Rle1::encode(data.clone())),
+        (FuzzTarget::If, If::encode(data.clone())),
+        (FuzzTarget::Tokens, Tokens::new(default_pipeline()).compress(data)),
+    ];
+    let mut varint = vec![];
+    push_varint(&mut varint, u64::MAX);
+    for (target, stream) in streams {
+        for offset in 0..stream.len().min(40) {
+            for field in [&varint[..], &u64::MAX.to_be_bytes(), &(1u64 << 40).to_be_bytes()] {
+                let mut inserted = stream.clone();
+                inserted.splice(offset..offset, field.iter().copied());
+                fuzz::run(target, &inserted);
+
+                let mut overwritten = stream.clone();
+                let end = (offset + field.len()).min(overwritten.len());
+                overwritten[offset..end].copy_from_slice(&field[..end - offset]);
+                fuzz::run(target, &overwritten);
+            }
+        }
+    }
+}
+
+#[test]
+fn global_flags_before_subcommands() {
+    use clap::Parser;
+
+    let parse = |argv: &[&str]| Args::try_parse_from(["compression-v2"].iter().chain(argv)).unwrap();
+    let args = parse(&["-q", "extract", "t.pka"]);
+    assert!(matches!(args.command, Some(Command::Extract { ref archive, .. }) if archive == "t.pka"));
+    assert!(args.quiet && args.input_paths.is_empty());
+
+    assert!(matches!(parse(&["-q", "list", "x.pka"]).command, Some(Command::List { .. })));
+    assert!(matches!(parse(&["-q", "info", "--json", "f.pkz"]).command, Some(Command::Info { json: true, .. })));
+    assert!(matches!(parse(&["--best", "create", "a.pka", "dir"]).command, Some(Command::Create { .. })));
+    // Stdin is limited unless asked not to be, local files only when asked to be
+    assert_eq!(parse(&["-d", "-"]).decode_limits(true), DecodeLimits::UNTRUSTED);
+    assert_eq!(parse(&["-d", "x.pkz"]).decode_limits(false), DecodeLimits::UNLIMITED);
+    let args = parse(&["-d", "-", "--max-output-size", "none", "--max-memory", "none"]);
+    assert_eq!(args.decode_limits(true), DecodeLimits::UNLIMITED);
+    assert_eq!(parse(&["-d", "x.pkz", "--max-memory", "1m"]).decode_limits(false).max_memory, 1_000_000);
+
+    // Without a subcommand, everything is still an input
+    assert_eq!(parse(&["-q", "a.txt", "b.txt"]).input_paths, ["a.txt", "b.txt"]);
+    // Stdin among other inputs doesn't send them all to stdout, but does keep the logs out of it
+    let args = parse(&["a.txt", "-"]);
+    assert!(!args.to_stdout() && args.reads_stdin());
+    assert!(!parse(&["a.txt"]).reads_stdin());
+}
+
+#[test]
+fn block_map_must_match_the_data() {
+    // A block map claiming 2^62 bytes in a single coded block, followed by 4 bytes of data
+    let mut file = b"PKZ\x04\x00\x00".to_vec();
+    push_varint(&mut file, 1 << 62);
+    push_varint(&mut file, 1 << 62);
+    file.extend(b"\x00data");
+    let error = Tokens::new(vec![]).decompress(file).unwrap_err().to_string();
+    assert!(error.contains("block map"), "{error}");
+
+    // Flagging a block past the end of the input
+    let mut file = b"PKZ\x04\x00\x00".to_vec();
+    push_varint(&mut file, 4);
+    push_varint(&mut file, 4);
+    file.extend(b"\x02data");
+    assert!(Tokens::new(vec![]).decompress(file).is_err());
 }
@@ -1,5 +1,19 @@
-use clap::{arg, command, Parser};
-use std::{collections::HashMap, fs::metadata, hash::Hash, io};
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use std::{
+    collections::HashMap,
+    fs::{self, metadata},
+    hash::Hash,
+    io::{self, IsTerminal, Read},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{bench::BenchPipeline, fuzz::FuzzTarget, stats::StatsFormat};
+use crate::encoders::{
+    auto,
+    encoder::{check_pipeline, default_pipeline, level_preset, Encoding, FileInfo},
+    limits::DecodeLimits,
+};
 
 /*
     This is a utlility file which contains various helper-functions used throughout this project.
@@ -11,11 +25,14 @@ use std::{collections::HashMap, fs::metadata, hash::Hash, io};
 
 /// Compresses an input file into an output (with extension .pkz)
 #[derive(Debug, Parser)]
-#[command(about)]
+#[command(about, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// File to compress
-    #[arg(required = true)]
-    pub input_path: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Files to compress, or `-` to read from stdin (the default when stdin isn't a terminal).
+    /// Output read from stdin always goes to stdout. Every file gets its own output.
+    pub input_paths: Vec<String>,
 
     /// Decompress instead of Compress. Expects a .pkz file as input.
     #[arg(short, long, default_value_t = false)]
@@ -25,21 +42,356 @@ pub struct Args {
     #[arg(short, long, default_value_t = false)]
     pub stdout: bool,
 
-    /// Hide debug output.
+    /// Write the output to this path instead of `<input>.pkz` (or the input minus `.pkz`). `-` is stdout.
+    #[arg(short, long, conflicts_with = "stdout")]
+    pub output: Option<String>,
+
+    /// Overwrite the output file if it already exists (or, with `extract`, any file the archive contains)
+    #[arg(short, long, default_value_t = false, global = true)]
+    pub force: bool,
+
+    /// Keep the input file once done (the default)
+    #[arg(short, long, default_value_t = false, overrides_with = "rm")]
+    pub keep: bool,
+
+    /// Delete the input file once its output has been written, like gzip and bzip2 do by default
+    #[arg(long, default_value_t = false, overrides_with = "keep")]
+    pub rm: bool,
+
+    /// Go through directories recursively. When decompressing (or with --test and --list), only their .pkz files
+    /// are picked up, and when compressing, those are skipped.
     #[arg(short, long, default_value_t = false)]
+    pub recursive: bool,
+
+    /// Number of files to work on at once. Defaults to the number of CPUs.
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: Option<u32>,
+
+    /// How to report statistics. `json` writes one object per file (sizes, ratio, pipeline, per-stage sizes
+    /// and timings, and the -c result) to stderr, or to --stats-file.
+    #[arg(long, value_enum, default_value_t = StatsFormat::Text)]
+    pub stats_format: StatsFormat,
+
+    /// Append the JSON statistics to this file instead of writing them to stderr. Implies --stats-format json.
+    #[arg(long)]
+    pub stats_file: Option<String>,
+
+    /// Hide debug output.
+    #[arg(short, long, default_value_t = false, global = true)]
     pub quiet: bool,
 
-    /// Provide a custom Encoding Pipeline in a space-separated list. Ignored if --decompress is used. 
-    /// 
-    /// Possible options (also the default): Bwt Mtf Rle Huff 
-    #[arg(short, long, value_delimiter = ' ', num_args = 1..)]
-    pub pipeline: Option<Vec<String>>,
+    /// Provide a custom Encoding Pipeline in a space-separated list. Ignored if --decompress is used.
+    ///
+    /// Possible options: Rle1 Delta Bwt Mtf Mtf1 Mtf2 Wfc If Rle Huff
+    ///
+    /// Stages can take parameters as `name:key=value[,key=value]`:
+    /// bwt:block=900k, mtf:variant=mtf|mtf1|mtf2|wfc, huff:tables=1..6, delta:stride=1..255
+    ///
+    /// Default: Bwt Mtf Rle Huff, with Rle1 in front if the input is highly repetitive
+    #[arg(short, long, value_delimiter = ' ', num_args = 1.., global = true)]
+    pub pipeline: Option<Vec<Encoding>>,
+
+    /// Compression level, from 1 (fastest) to 9 (best). Also accepted as -1 ... -9. Ignored if --decompress is used.
+    ///
+    /// Each level picks a preset pipeline, BWT block size and number of Huffman tables.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=9), conflicts_with = "pipeline", global = true)]
+    pub level: Option<u8>,
+
+    /// Same as -1
+    #[arg(long, default_value_t = false, conflicts_with_all = ["level", "pipeline", "best"], global = true)]
+    pub fast: bool,
+
+    /// Same as -9
+    #[arg(long, default_value_t = false, conflicts_with_all = ["level", "pipeline"], global = true)]
+    pub best: bool,
+
+    /// Try a few candidate pipelines on samples of the input and use whichever compresses best.
+    /// Ignored if --decompress is used.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["pipeline", "level", "fast", "best"], global = true)]
+    pub auto: bool,
+
+    /// Time budget for --auto in milliseconds. At least one pipeline is always tried.
+    #[arg(long, default_value_t = 2000, requires = "auto", global = true)]
+    pub auto_budget: u64,
+
+    /// Show what's inside a .pkz (pipeline, sizes and stage headers) without writing anything.
+    /// Reading the stage headers decodes every stage but the innermost one (usually the BWT).
+    #[arg(short, long, default_value_t = false, conflicts_with_all = ["decompress", "check"])]
+    pub list: bool,
+
+    /// Decode a .pkz (or every member of a .pka) in memory and check it against its stored size and checksum,
+    /// without writing anything. Exits with 1 if anything fails.
+    #[arg(short, long, default_value_t = false, conflicts_with_all = ["decompress", "check", "list", "stdout"])]
+    pub test: bool,
+
+    /// Print --list as JSON
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+
+    /// Write the exact bytes between every two stages to numbered files in this directory
+    /// (`encode-02-mtf.bin`, `decode-02-mtf.bin`...), for `inspect`. With -c, both directions get written.
+    #[arg(long, value_name = "DIR")]
+    pub dump_stages: Option<String>,
+
+    /// Write the Huffman tree(s) to this file as a Graphviz DOT graph (render it with `dot -Tsvg`).
+    /// Works both ways: the tree built when compressing, or the one rebuilt from the header when decompressing.
+    #[arg(long, value_name = "FILE")]
+    pub huff_dot: Option<String>,
+
+    /// Write the Huffman code table (byte, frequency, code length and code) to this file
+    #[arg(long, value_name = "FILE")]
+    pub huff_codes: Option<String>,
+
+    /// Don't save the original file name, permissions and modification time when compressing,
+    /// and don't restore them when decompressing
+    #[arg(short, long, default_value_t = false)]
+    pub no_name: bool,
+
+    /// Decode every stage right after encoding it and compare the result with that stage's input, stopping at the
+    /// first stage that doesn't invert. Slower, but says which stage (and offset) is to blame when -c fails.
+    /// Ignored if --decompress is used.
+    #[arg(long, default_value_t = false)]
+    pub paranoid: bool,
+
+    /// Refuse to decode any file (or stage of one) that would come out larger than this, like `500m` or `2g`,
+    /// since a header can claim any size at all. `none` lifts the limit.
+    /// Default: no limit for files, 2g for stdin
+    #[arg(long, value_name = "SIZE", value_parser = parse_limit, global = true)]
+    pub max_output_size: Option<usize>,
+
+    /// Refuse to decode any file whose stages would need more memory than this, like `500m` or `2g`.
+    /// Counts each stage's output along with its working tables (the BWT needs about 100 bytes per byte
+    /// of a block). `none` lifts the limit.
+    /// Default: no limit for files, 4g for stdin
+    #[arg(long, value_name = "SIZE", value_parser = parse_limit, global = true)]
+    pub max_memory: Option<usize>,
 
     /// Performs the compression and verifies that it decodes to the original content. Ignored if --decompress is used.
     #[arg(short, long = "check-integrity", default_value_t = false)]
     pub check: bool
 }
 
+/// Archive mode, for packing multiple files and directories into a single .pka file
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Creates a .pka archive from files and directories. Every file is compressed with the chosen pipeline.
+    Create {
+        /// Archive to write
+        archive: String,
+
+        /// Files and directories to add. Directories are added recursively.
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
+    /// Extracts every member of a .pka archive
+    Extract {
+        /// Archive to extract
+        archive: String,
+
+        /// Directory to extract into
+        #[arg(short = 'C', long, default_value = ".")]
+        directory: String,
+    },
+
+    /// Lists the members of a .pka archive without extracting them
+    List {
+        /// Archive to list
+        archive: String,
+    },
+
+    /// Profiles files before compressing them: byte histogram, entropy, runs and estimated output per pipeline
+    Analyze {
+        /// Files to profile, or `-` for stdin
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+
+    /// Shows the header of every stage, from a --dump-stages directory (both directions side by side) or a .pkz
+    Inspect {
+        /// --dump-stages directories or .pkz files
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
+    /// Benchmarks pipelines on every file in a directory: ratio, speed, peak memory and round trip
+    Bench {
+        /// Directory with the files to benchmark, searched recursively
+        dir: String,
+
+        /// Pipeline to benchmark, as a space-separated list of stages (like -p) or a level from 1 to 9.
+        /// Can be given several times. Default: the default pipeline, -1 and -9.
+        #[arg(short, long = "with", value_name = "PIPELINE")]
+        with: Vec<BenchPipeline>,
+
+        /// Also write the results to this CSV file
+        #[arg(long)]
+        csv: Option<String>,
+    },
+
+    /// Shows what's inside .pkz files (pipeline, sizes and stage headers), same as --list
+    Info {
+        /// Files to inspect
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Print as JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+
+    /// Runs a decoder on stdin, for fuzzing (see src/fuzz.rs)
+    #[command(hide = true)]
+    Fuzz {
+        /// Decoder to run
+        #[arg(value_enum)]
+        target: FuzzTarget,
+    },
+}
+
+impl Args {
+    /// Parses the CLI args, after turning gzip-style `-1` ... `-9` into `--level`
+    pub fn parse_with_levels() -> Self {
+        let args = std::env::args_os().map(|arg| match arg.to_str().map(str::as_bytes) {
+            Some(&[b'-', level @ b'1'..=b'9']) => format!("--level={}", level as char).into(),
+            _ => arg,
+        });
+        let args = Self::parse_from(args);
+        // clap only sees the stages one at a time
+        if let Some(Err(e)) = args.pipeline.as_deref().map(check_pipeline) {
+            Self::command().error(ErrorKind::ValueValidation, e).exit();
+        }
+        args
+    }
+
+    /// The input files, with directories expanded if -r was passed (and skipped otherwise), and `-` for stdin.
+    /// Exits with a usage error if there are none and stdin is a terminal.
+    pub fn inputs(&self) -> io::Result<Vec<String>> {
+        if self.input_paths.is_empty() {
+            if io::stdin().is_terminal() {
+                Self::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "Expected an input file, or `-` (or data piped in) to read from stdin",
+                    )
+                    .exit();
+            }
+            return Ok(vec!["-".to_string()]);
+        }
+
+        let mut inputs = vec![];
+        for path in self.input_paths.iter() {
+            if path == "-" || !Path::new(path).is_dir() {
+                inputs.push(path.clone());
+            } else if self.recursive {
+                let files = walk_files(Path::new(path))?;
+                inputs.extend(
+                    files
+                        .iter()
+                        .filter(|file| self.reads_pkz() == (file.extension() == Some("pkz".as_ref())))
+                        .map(|file| file.to_string_lossy().into_owned()),
+                );
+            } else {
+                log::warn!("{path} is a directory, skipping it (pass -r to go through it)");
+            }
+        }
+        Ok(inputs)
+    }
+
+    /// Whether the inputs go through the thread pool (see `batch`): several were given, or a directory is walked
+    /// with -r. Known before `inputs` expands them, so the logger can be set up for it.
+    pub fn batch(&self) -> bool {
+        self.input_paths.len() > 1 || (self.recursive && self.input_paths.iter().any(|path| Path::new(path).is_dir()))
+    }
+
+    /// Whether the inputs are supposed to be .pkz files, rather than files to compress
+    fn reads_pkz(&self) -> bool {
+        self.decompress || self.test || self.list
+    }
+
+    /// Whether the output goes to stdout, which it does by default when reading from stdin
+    pub fn to_stdout(&self) -> bool {
+        match self.output.as_deref() {
+            Some(output) => output == "-",
+            None => self.stdout || self.input_paths.is_empty() || self.input_paths == ["-"],
+        }
+    }
+
+    /// Whether one of the inputs is stdin, whose output always goes to stdout
+    pub fn reads_stdin(&self) -> bool {
+        self.input_paths.is_empty() || self.input_paths.iter().any(|path| path == "-")
+    }
+
+    /// The compression level asked for, if any
+    pub fn level(&self) -> Option<u8> {
+        match (self.fast, self.best) {
+            (true, _) => Some(1),
+            (_, true) => Some(9),
+            _ => self.level,
+        }
+    }
+
+    /// What decoding is limited to by --max-output-size and --max-memory.
+    /// Without them, local files aren't limited at all, while stdin (which may come from anywhere) is.
+    pub fn decode_limits(&self, from_stdin: bool) -> DecodeLimits {
+        let defaults = match from_stdin {
+            true => DecodeLimits::UNTRUSTED,
+            false => DecodeLimits::UNLIMITED,
+        };
+        DecodeLimits {
+            max_output_size: self.max_output_size.unwrap_or(defaults.max_output_size),
+            max_memory: self.max_memory.unwrap_or(defaults.max_memory),
+        }
+    }
+
+    /// Resolves the flags into the concrete Encoding pipeline to compress `input` with
+    pub fn resolve_pipeline(&self, input: &[u8]) -> Vec<Encoding> {
+        if let Some(pipeline) = &self.pipeline {
+            return pipeline.clone();
+        }
+
+        let mut pipeline = match (self.auto, self.level()) {
+            (true, _) => auto::search(input, Duration::from_millis(self.auto_budget)),
+            (false, Some(level)) => level_preset(level),
+            (false, None) => default_pipeline(),
+        };
+
+        // Long runs are the worst case for the suffix sort, so cap them before they reach the BWT
+        if matches!(pipeline.first(), Some(Encoding::Bwt { .. })) && is_highly_repetitive(input) {
+            pipeline.insert(0, Encoding::Rle1);
+        }
+        pipeline
+    }
+}
+
+/// Recursively lists the regular files under `dir`, in sorted order
+pub fn walk_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut children: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|child| child.map(|c| c.path()))
+        .collect::<Result<_, _>>()?;
+    children.sort();
+
+    let mut files = vec![];
+    for child in children {
+        if child.is_dir() {
+            files.extend(walk_files(&child)?);
+        } else if child.is_file() {
+            files.push(child);
+        }
+    }
+    Ok(files)
+}
+
+/// Reads the whole file at `path`, or all of stdin if `path` is `-`
+pub fn read_input(path: &str) -> io::Result<Vec<u8>> {
+    if path != "-" {
+        return fs::read(path);
+    }
+    let mut data = vec![];
+    io::stdin().lock().read_to_end(&mut data)?;
+    Ok(data)
+}
+
 /// Enumerates duplicates within a `Vec<T>` to `Vec<(T, usize)`, count starts at `0`.
 pub fn enumerate_duplicates<T>(v: Vec<T>) -> Vec<(T, usize)>
 where
@@ -59,6 +411,48 @@ where
         .collect()
 }
 
+/// Parses a size like `900k`, `4m` or `2g` (powers of 1000, like bzip2's block sizes). Plain numbers are bytes.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.to_lowercase();
+    let (number, multiplier) = match s.strip_suffix('k') {
+        Some(n) => (n, 1_000),
+        None => match s.strip_suffix('m') {
+            Some(n) => (n, 1_000_000),
+            None => match s.strip_suffix('g') {
+                Some(n) => (n, 1_000_000_000),
+                None => (s.as_str(), 1),
+            },
+        },
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// `parse_size` for clap, for --max-output-size and --max-memory, with `none` for no limit
+fn parse_limit(s: &str) -> Result<usize, String> {
+    if s.eq_ignore_ascii_case("none") {
+        return Ok(usize::MAX);
+    }
+    parse_size(s).ok_or(format!("expected a size like 500m or 2g (or none), got `{s}`"))
+}
+
+/// Output size as a percentage of the input size (100% for an empty input)
+pub fn ratio(output: usize, input: usize) -> f64 {
+    match input {
+        0 => 100.0,
+        _ => output as f64 / input as f64 * 100.0,
+    }
+}
+
+/// Formats a size the way `parse_size` reads it, using the largest suffix that divides it evenly
+pub fn format_size(size: usize) -> String {
+    match size {
+        0 => "0".to_string(),
+        s if s % 1_000_000 == 0 => format!("{}m", s / 1_000_000),
+        s if s % 1_000 == 0 => format!("{}k", s / 1_000),
+        s => s.to_string(),
+    }
+}
+
 /// Helper function to convert a `u32` in base-10 to a different base (usually base-36)
 pub fn format_radix(mut x: u32, radix: u32) -> String {
     let mut result = vec![];
@@ -76,68 +470,204 @@ pub fn format_radix(mut x: u32, radix: u32) -> String {
     result.into_iter().rev().collect()
 }
 
-/// Retrieves the least used byte in a Vec<u8>. An unused byte will always be returned if present.
-pub fn get_least_used_byte(input: &Vec<u8>) -> u8 {
-    // First we run through with a bitmask
-    let mut upper = u128::MIN;
-    let mut lower = u128::MIN;
+/// Appends `value` to `output` as a LEB128 varint (7 bits per byte, high bit set on all but the last byte)
+pub fn push_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
 
-    input.iter().for_each(|&b| match b {
-        0..=127 => lower |= (0x1 as u128) << b,
-        128..=255 => upper |= (0x1 as u128) << (b - 128),
-    });
+/// Reads a LEB128 varint from the front of `input`, advancing it past the varint.
+/// Returns `None` if the input ends mid-varint or the value doesn't fit in a `u64`.
+pub fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
 
-    log::info!("Bitmask {:0128b} {:0128b}", upper, lower);
+        if shift > 63 || (shift == 63 && byte > 1) {
+            return None;
+        }
+        value |= ((byte & 0x7F) as u64) << shift;
 
-    if lower != u128::MAX {
-        for i in 0..=127 {
-            // TODO: figure out why backslash can't be used as a delimiter
-            if (!lower >> i) % 2 == 1 && i != 92 {
-                return i as u8;
-            }
+        if byte & 0x80 == 0 {
+            return Some(value);
         }
+        shift += 7;
     }
+}
 
-    if upper != u128::MAX {
-        for i in 0..=127 {
-            if (!lower >> i) % 2 == 1 {
-                return i as u8 + 128;
-            }
+/// Returns true if long runs of a single byte make up a large part of `input`,
+/// which is the worst case for suffix sorting.
+/// Specifically, checks if at least a quarter of the input would be removed by capping every run at 5 bytes.
+pub fn is_highly_repetitive(input: &[u8]) -> bool {
+    let mut redundant = 0;
+    let mut index = 0;
+    while index < input.len() {
+        let run = input[index..].iter().take_while(|&&b| b == input[index]).count();
+        redundant += run.saturating_sub(5);
+        index += run;
+    }
+    !input.is_empty() && redundant * 4 >= input.len()
+}
+
+/// Estimates how many bits per byte an order-0 entropy coder (like Huffman) would need for `data`, from 0 to 8
+pub fn order0_entropy(data: &[u8]) -> f64 {
+    let mut freq_map = [0usize; 256];
+    data.iter().for_each(|&b| freq_map[b as usize] += 1);
+    freq_map
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / data.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// CRC-32 lookup table (the reflected 0xEDB88320 polynomial used by gzip and zip)
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
         }
+        table[i] = crc;
+        i += 1;
     }
+    table
+};
 
-    // If the bitmask failed (aka all 256 bytes were present, use the more expensive HashMap appraoch)
-    let mut map: HashMap<u8, usize> = HashMap::new();
+/// CRC-32 of `data`, the same one gzip stores
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
 
-    input.iter().for_each(|&b| {
-        map.entry(b).and_modify(|v| *v += 1).or_insert(1);
-    });
+/// Reads the name, permissions and modification time of the file at `path`, to be stored in the .pkz header
+pub fn read_file_info(path: &str) -> io::Result<FileInfo> {
+    let metadata = fs::metadata(path)?;
 
-    // TODO: figure out why backslash can't be used as a delimiter
-    map.remove(&b'\\');
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        // Just the permission bits, the file type is always a regular file here
+        Some(metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
 
-    map.into_iter()
-        .min_by_key(|(_byte, count)| *count)
-        .unwrap_or((0, 0)) // This default will almost never get reached since it means the input is empty
-        .0
+    Ok(FileInfo {
+        name: Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        mode,
+        mtime: metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|since| since.as_secs()),
+        size: Some(metadata.len()),
+        checksum: None,
+    })
 }
 
-/// Inserts a `insert_byte` before all occurrences of `target_byte` in `input`
-pub fn insert_before_target(input: &mut Vec<u8>, target_byte: u8, insert_byte: u8) {
-    let mut index = 0;
-    while index < input.len() {
-        if input[index] == target_byte {
-            // Insert a backslash before the target byte
-            input.insert(index, insert_byte);
-            // Move past the inserted backslash and delim
-            index += 2;
-        } else {
-            index += 1;
+/// Restores the permissions and modification time from the .pkz header onto the file at `path`
+pub fn restore_file_info(path: &str, info: &FileInfo) -> io::Result<()> {
+    if let Some(mtime) = info.mtime {
+        let file = fs::File::options().write(true).open(path)?;
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = info.mode {
+        use std::os::unix::fs::PermissionsExt;
+        // Headers written before the file type was masked off still carry it
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    Ok(())
+}
+
+/// Offset of the first byte where `a` and `b` differ (or where the shorter one ends), None if they're equal
+pub fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(offset) => Some(offset),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// The character for a byte in quotes, if it is printable
+pub fn printable(byte: u8) -> String {
+    match byte {
+        b' ' => "' '".to_string(),
+        _ if byte.is_ascii_graphic() => format!("'{}'", byte as char),
+        _ => String::new(),
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut output = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
         }
     }
+    output.push('"');
+    output
+}
+
+/// Writes `fields` as a JSON object. The values have to be JSON already, see `json_string`.
+pub fn json_object<K: AsRef<str>>(fields: &[(K, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}:{value}", json_string(key.as_ref())))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD hh:mm:ss UTC`
+pub fn format_timestamp(secs: u64) -> String {
+    let (days, time) = (secs / 86_400, secs % 86_400);
+
+    // Converts days since the epoch to a civil date (Howard Hinnant's `civil_from_days`)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3_600,
+        time / 60 % 60,
+        time % 60
+    )
 }
 
-#[allow(unused)]
+#[allow(unused, clippy::needless_return)]
 /// Gets the file size given a path. Unified functionality across different OS's.
 pub fn get_file_size(path: &str) -> io::Result<u64> {
     #[cfg(target_os = "windows")]
@@ -152,16 +682,3 @@ pub fn get_file_size(path: &str) -> io::Result<u64> {
         return Ok(metadata(path)?.st_size());
     }
 }
-
-/// Returns the index of the first instance of an object
-pub fn index_of<T>(v: &Vec<T>, obj: &T) -> Option<usize>
-where
-    T: Eq,
-{
-    for index in 0..v.len() {
-        if v[index] == *obj {
-            return Some(index);
-        }
-    }
-    None
-}
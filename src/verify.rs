@@ -0,0 +1,59 @@
+use colored::Colorize;
+
+use crate::{
+    archive::{self, EntryKind},
+    encoders::{encoder::Tokens, limits::DecodeLimits},
+    utils::read_input,
+};
+
+/*
+    -t / --test: decodes a .pkz (or every member of a .pka) in memory and throws the result away.
+
+    `Tokens::decompress` already checks the decoded data against the size and CRC-32 in the header,
+    so a file passes if it decodes at all. Files written before the checksum existed can only be checked
+    for decoding without errors (and against their size, if they have one).
+    Nothing is ever written to disk.
+*/
+
+/// Tests `path`, printing one line per file (or archive member). Returns whether everything passed.
+/// A file that can't be read fails like one that doesn't decode.
+pub fn test(path: &str, limits: &DecodeLimits) -> bool {
+    let data = match read_input(path) {
+        Ok(data) => data,
+        Err(e) => return report(path, Err(e.to_string())),
+    };
+
+    if !archive::is_archive(&data) {
+        let mut tokens = Tokens::new(vec![]);
+        tokens.limits = *limits;
+        let result = tokens.decompress(data).map(|_| ()).map_err(|e| e.reason().to_string());
+        return report(path, result);
+    }
+
+    let index = match archive::read_index(&data) {
+        Ok(index) => index,
+        Err(e) => return report(path, Err(e.reason().to_string())),
+    };
+    let mut passed = true;
+    for entry in index.iter().filter(|e| e.kind == EntryKind::File) {
+        let result = archive::read_member(&data, entry, limits)
+            .map(|_| ())
+            .map_err(|e| e.reason().to_string());
+        passed &= report(&format!("{path}:{}", entry.path), result);
+    }
+    passed
+}
+
+/// Prints whether `name` passed (or why it didn't), returning the same
+fn report(name: &str, result: Result<(), String>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("{name}: {}", "OK".green());
+            true
+        }
+        Err(reason) => {
+            println!("{name}: {} ({reason})", "FAILED".red().bold());
+            false
+        }
+    }
+}